@@ -0,0 +1,182 @@
+use std::fs::File;
+use std::ops::Range;
+use std::path::Path;
+use memmap::Mmap;
+use nom::IResult;
+
+use types::*;
+use parser;
+use errors::*;
+
+/// Where an entry's details are in the map, so they can be handed out again without re-parsing.
+struct EntryLocation {
+    file_type: DDFiletype,
+    filename: Range<usize>,
+    offset: u32,
+    size: u32,
+    timestamp: u32
+}
+
+/// A read-only view of an archive that's been mapped into memory.
+///
+/// The header is parsed once when it's opened, and nothing else is read out of the file until it's used.
+/// Nothing is copied out of the map either: entries and their filenames are handed out as slices pointing straight into it.
+pub struct MappedArchive {
+    map: Mmap,
+    header: DDMainHeader,
+    entries: Vec<EntryLocation>,
+    folders: Vec<Option<String>>
+}
+
+impl MappedArchive {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path)
+            .chain_err(|| format!("Failed to open archive {}", path.display()))?;
+        // Safety: the map is only ever read from, but if something else truncates or
+        // rewrites the archive while we have it open, all bets are off.
+        let map = unsafe { Mmap::map(&file) }
+            .chain_err(|| format!("Failed to map archive {} into memory", path.display()))?;
+        MappedArchive::from_map(map)
+    }
+
+    pub fn from_map(map: Mmap) -> Result<Self> {
+        let (header, entries, folders) = match parser::header_section_ref(&map) {
+            IResult::Done(_, (header, files)) => {
+                // Make sure every entry actually fits inside the file,
+                // so handing out slices later can't go out of bounds.
+                for file in &files {
+                    if file.offset as usize + file.size as usize > map.len() {
+                        return Err(format!("Entry {} (offset {}, size {}) runs past the end of the archive",
+                                           file.filename_lossy(), file.offset, file.size).into());
+                    }
+                }
+                let base = map.as_ptr() as usize;
+                let entries = files.iter().map(|file| {
+                    // The parser borrows filenames straight from the map, so this is where they start in it
+                    let start = file.filename.as_ptr() as usize - base;
+                    EntryLocation {
+                        file_type: file.file_type,
+                        filename: start..start + file.filename.len(),
+                        offset: file.offset,
+                        size: file.size,
+                        timestamp: file.timestamp
+                    }
+                }).collect();
+                (header, entries, folders_of(&files))
+            },
+            IResult::Incomplete(_) => return Err("Archive header is truncated".into()),
+            IResult::Error(e) => return Err(format!("Failed to parse archive header: {:?}", e).into())
+        };
+        Ok(MappedArchive { map, header, entries, folders })
+    }
+
+    pub fn header(&self) -> &DDMainHeader {
+        &self.header
+    }
+
+    /// Every entry in the archive, in the order they're listed in the header.
+    pub fn entries(&self) -> Vec<DDSubFileRef<'_>> {
+        self.entries.iter().map(|entry| DDSubFileRef {
+            file_type: entry.file_type,
+            filename: &self.map[entry.filename.clone()],
+            offset: entry.offset,
+            size: entry.size,
+            timestamp: entry.timestamp
+        }).collect()
+    }
+
+    /// The folder each entry goes in, going by the folder markers, in the same order as `entries`.
+    pub fn entry_folders(&self) -> &[Option<String>] {
+        &self.folders
+    }
+
+    /// Finds an entry of type `file_type` by name, which can include its folder, like `sub/name`.
+    ///
+    /// Names aren't unique on their own: a texture, a model and a shader can all be called `boid`.
+    pub fn find(&self, name: &str, file_type: DDFiletype) -> Option<DDSubFileRef<'_>> {
+        self.entries().into_iter().zip(&self.folders)
+            .filter(|(entry, _)| entry.file_type == file_type)
+            .find(|(entry, folder)| {
+                let filename = entry.filename_lossy();
+                filename == name || folder.as_ref().is_some_and(|f| format!("{}/{}", f, filename) == name)
+            })
+            .map(|(entry, _)| entry)
+    }
+
+    /// The contents of an entry.
+    ///
+    /// `entry` has to have come from this archive's `entries`.
+    pub fn data(&self, entry: &DDSubFileRef) -> &[u8] {
+        let start = entry.offset as usize;
+        &self.map[start..start + entry.size as usize]
+    }
+
+    /// The whole archive, header and all.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.map
+    }
+}
+
+/// Works out which folder each entry goes in.
+///
+/// A folder's marker comes after the files in it, so each entry belongs to the next marker along.
+/// Entries after the last marker aren't in any folder, and neither are the markers themselves.
+fn folders_of(files: &[DDSubFileRef]) -> Vec<Option<String>> {
+    let mut folder = None;
+    let mut folders: Vec<Option<String>> = files.iter().rev().map(|entry| {
+        if entry.file_type == DDFiletype::FolderMarker {
+            folder = Some(entry.filename_lossy().into_owned());
+            None
+        } else {
+            folder.clone()
+        }
+    }).collect();
+    folders.reverse();
+    folders
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memmap::MmapMut;
+
+    /// Lays out an archive the same way pack does: the header, then each entry's contents in order.
+    fn archive_of(files: &[(DDFiletype, &str, &[u8])]) -> MappedArchive {
+        let header_length = files.iter().fold(2, |acc, (_, name, _)| acc + 2 + name.len() as u32 + 1 + 12);
+        let mut data = vec![];
+        DDMainHeader::new(header_length).write(&mut data).unwrap();
+        let mut offset = header_length + 12;
+        for &(file_type, name, contents) in files {
+            let header = DDSubFileHeader { file_type, filename: name.to_string(), offset, size: contents.len() as u32, timestamp: 0 };
+            header.write(&mut data).unwrap();
+            offset += contents.len() as u32;
+        }
+        data.extend_from_slice(&[0, 0]);
+        for (_, _, contents) in files {
+            data.extend_from_slice(contents);
+        }
+        let mut map = MmapMut::map_anon(data.len()).unwrap();
+        map.copy_from_slice(&data);
+        MappedArchive::from_map(map.make_read_only().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn find_picks_the_type_asked_for() {
+        let archive = archive_of(&[
+            (DDFiletype::ShaderText, "boid", b"shader"),
+            (DDFiletype::Texture1, "boid", b"model"),
+            (DDFiletype::Texture2, "boid", b"texture"),
+            (DDFiletype::Texture2, "boid", b"other"),
+            (DDFiletype::FolderMarker, "sub", b""),
+            (DDFiletype::Texture1, "boid", b"top model")
+        ]);
+        let find = |name, file_type| archive.find(name, file_type).map(|entry| archive.data(&entry));
+        assert_eq!(find("boid", DDFiletype::ShaderText), Some(&b"shader"[..]));
+        assert_eq!(find("boid", DDFiletype::Texture1), Some(&b"model"[..]));
+        assert_eq!(find("boid", DDFiletype::Texture2), Some(&b"texture"[..]));
+        assert_eq!(find("sub/boid", DDFiletype::Texture2), Some(&b"texture"[..]));
+        assert_eq!(find("boid", DDFiletype::GLSL), None);
+        assert_eq!(find("sub/boid", DDFiletype::FolderMarker), None);
+    }
+}
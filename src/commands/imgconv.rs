@@ -1,80 +1,803 @@
 
 use clap::ArgMatches;
-use nom::IResult;
-use image::{self, GenericImage, ImageBuffer};
+use image::{imageops, DynamicImage, GenericImageView, GrayImage, ImageBuffer, ImageEncoder, ImageFormat, ImageOutputFormat, Luma, Rgba, RgbaImage};
+use image::codecs::pnm::{PnmSubtype, SampleEncoding};
+use image::codecs::tiff::TiffEncoder;
+use image::io::Reader as ImageReader;
 
 use std::io::prelude::*;
-use std::io::{self, BufReader};
-use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::fs::{self, File};
 use std::path::{Path, PathBuf};
-use std::process::exit;
 
+use super::super::archive::MappedArchive;
+use super::super::encoding::{from_hex, to_hex};
+use super::super::sidecar::Sidecar;
 use super::super::tex2;
+use super::super::types::DDFiletype;
 use super::super::errors::*;
+use super::jobs;
+
+/// Every image format imgconv can read and write, by name and extension.
+const IMAGE_FORMATS: &[(&str, ImageFormat)] = &[
+    ("png", ImageFormat::Png),
+    ("bmp", ImageFormat::Bmp),
+    ("tga", ImageFormat::Tga),
+    ("tiff", ImageFormat::Tiff),
+    ("tif", ImageFormat::Tiff),
+    ("jpeg", ImageFormat::Jpeg),
+    ("jpg", ImageFormat::Jpeg),
+    ("ico", ImageFormat::Ico),
+    ("pnm", ImageFormat::Pnm),
+    ("ppm", ImageFormat::Pnm),
+    ("pgm", ImageFormat::Pnm)
+];
+
+/// Where a texture being converted as part of a batch comes from.
+enum BatchSource<'a> {
+    File(PathBuf),
+    /// An archive entry's contents
+    Entry(&'a [u8])
+}
+
+struct BatchJob<'a> {
+    /// What to call the texture when talking about it
+    name: String,
+    source: BatchSource<'a>,
+    output_file: PathBuf
+}
+
+struct BatchOptions {
+    format: ImageFormat,
+    mipmaps: bool,
+    pixels: PixelOptions
+}
+
+/// Changes made to a texture's pixels on their way out of it or into it.
+#[derive(Default)]
+pub struct PixelOptions {
+    /// Order the channels are stored in
+    pub order: tex2::ChannelOrder,
+    /// Whether the texture's colours are premultiplied by alpha
+    pub premultiplied: bool,
+    /// Whether alpha goes in an image of its own when exporting
+    pub split_alpha: bool
+}
+
+impl PixelOptions {
+    /// Options from the command line, going by `sidecar` for any that aren't given.
+    fn from_matches(matches: &ArgMatches, sidecar: Option<&Sidecar>) -> Result<Self> {
+        let mut options = PixelOptions::from_sidecar(sidecar)?;
+        if let Some(order) = matches.value_of("swizzle") {
+            options.order = tex2::ChannelOrder::from_name(order).unwrap();
+        }
+        options.premultiplied |= matches.is_present("premultiplied");
+        options.split_alpha |= matches.is_present("splitalpha");
+        Ok(options)
+    }
+
+    /// Options from `swizzle = ...`, `premultiplied = yes` and `splitalpha = yes` in a sidecar.
+    pub fn from_sidecar(sidecar: Option<&Sidecar>) -> Result<Self> {
+        let order = match sidecar.and_then(|s| s.get("swizzle")) {
+            Some(order) => tex2::ChannelOrder::from_name(order)
+                .ok_or_else(|| format!("Sidecar has unrecognized swizzle {}", order))?,
+            None => tex2::ChannelOrder::Rgba
+        };
+        Ok(PixelOptions {
+            order,
+            premultiplied: sidecar.is_some_and(|s| is_yes(s.get("premultiplied"))),
+            split_alpha: sidecar.is_some_and(splits_alpha)
+        })
+    }
+
+    /// Turns a texture's pixels into straight RGBA.
+    pub fn decode(&self, data: &mut [u8]) {
+        self.order.unswizzle(data);
+        if self.premultiplied {
+            tex2::unpremultiply(data);
+        }
+    }
+
+    /// Turns straight RGBA into what the texture should hold.
+    pub fn encode(&self, data: &mut [u8]) {
+        if self.premultiplied {
+            tex2::premultiply(data);
+        }
+        self.order.swizzle(data);
+    }
+}
+
+/// How to get an image to the size of the texture it's replacing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResizeMode {
+    /// Scale it to fit inside, leaving the rest transparent
+    Fit,
+    /// Scale it to cover the whole texture, cropping off whatever hangs over the edges
+    Fill,
+    /// Scale each way separately, never mind the aspect ratio
+    Stretch
+}
+
+impl ResizeMode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "fit" => Some(ResizeMode::Fit),
+            "fill" => Some(ResizeMode::Fill),
+            "stretch" => Some(ResizeMode::Stretch),
+            _ => None
+        }
+    }
+
+    /// Resamples `img` to exactly `width`x`height`.
+    pub fn resize(&self, img: &RgbaImage, width: u32, height: u32) -> RgbaImage {
+        let filter = imageops::FilterType::Lanczos3;
+        if *self == ResizeMode::Stretch {
+            return imageops::resize(img, width, height, filter);
+        }
+        // Same scale both ways, whichever side is the tight one for fit or the loose one for fill
+        let (x_scale, y_scale) = (width as f64 / img.width() as f64, height as f64 / img.height() as f64);
+        let scale = if *self == ResizeMode::Fit { x_scale.min(y_scale) } else { x_scale.max(y_scale) };
+        let scaled_width = ((img.width() as f64 * scale).round() as u32).max(1);
+        let scaled_height = ((img.height() as f64 * scale).round() as u32).max(1);
+        let scaled = imageops::resize(img, scaled_width, scaled_height, filter);
+        // Centre it, which pads it out for fit and crops it for fill
+        let (x, y) = ((width as i64 - scaled_width as i64) / 2, (height as i64 - scaled_height as i64) / 2);
+        ImageBuffer::from_fn(width, height, |px, py| {
+            let (sx, sy) = (px as i64 - x, py as i64 - y);
+            if sx >= 0 && sy >= 0 && sx < scaled_width as i64 && sy < scaled_height as i64 {
+                *scaled.get_pixel(sx as u32, sy as u32)
+            } else {
+                Rgba([0, 0, 0, 0])
+            }
+        })
+    }
+}
+
+/// The size and mipmap count of the texture an image is replacing.
+#[derive(Clone, Copy)]
+pub struct OriginalTexture {
+    pub width: u32,
+    pub height: u32,
+    /// Levels it had, counting the full-size image
+    pub mipmaps: u8
+}
+
+impl OriginalTexture {
+    /// What `originalwidth`, `originalheight` and `originalmipmaps` in an image's sidecar say,
+    /// if it has them.
+    pub fn from_sidecar(sidecar: Option<&Sidecar>) -> Result<Option<Self>> {
+        let field = |key: &str| -> Result<Option<u32>> {
+            match sidecar.and_then(|s| s.get(key)) {
+                Some(value) => value.parse::<u32>().map(Some)
+                    .chain_err(|| format!("Sidecar has {} = {}, which isn't a number", key, value)),
+                None => Ok(None)
+            }
+        };
+        match (field("originalwidth")?, field("originalheight")?, field("originalmipmaps")?) {
+            (Some(width), Some(height), mipmaps) if width > 0 && height > 0 => Ok(Some(OriginalTexture {
+                width,
+                height,
+                mipmaps: mipmaps.unwrap_or(1).clamp(1, tex2::max_mipmap_count(width, height) as u32) as u8
+            })),
+            _ => Ok(None)
+        }
+    }
+
+    pub fn from_texture(file: &Path) -> Result<Self> {
+        let texture = read_tex2(file).chain_err(|| format!("Failed to read texture {}", file.display()))?;
+        Ok(OriginalTexture { width: texture.width, height: texture.height, mipmaps: texture.mipmap_levels.max(1) })
+    }
+}
 
 pub fn execute(matches: &ArgMatches) -> Result<()> {
-    let mut tex2image = read_tex2(matches.value_of("FILE").unwrap()).chain_err(|| "Failed to open input file")?;
+    let input = PathBuf::from(matches.value_of("FILE").unwrap());
+    if matches.is_present("reverse") {
+        if input.is_dir() {
+            return Err("--reverse only converts one image at a time".into());
+        }
+        return encode(matches);
+    }
+    if input.is_dir() || is_archive(&input)? {
+        return batch(matches, &input);
+    }
 
-    let mut output_file = if matches.is_present("OUTFILE") {
+    let tex2image = read_tex2(matches.value_of("FILE").unwrap()).chain_err(|| "Failed to open input file")?;
+
+    let forced_format = matches.value_of("format").map(|f| image_format(f).unwrap());
+    let output_file = if matches.is_present("OUTFILE") {
         PathBuf::from(matches.value_of("OUTFILE").unwrap())
     } else {
         let mut file = PathBuf::from(matches.value_of("FILE").unwrap());
-        file.set_extension("png");
+        file.set_extension(matches.value_of("format").unwrap_or("png"));
         file
     };
+    let format = match forced_format {
+        Some(format) => format,
+        None => output_format(&output_file)?
+    };
 
-    let max_mipmap_levels = if matches.is_present("mipmaps") { tex2image.mipmap_levels } else { 1 } as usize;
+    let pixels = PixelOptions::from_matches(matches, None)?;
+    let (written, warnings) = save_levels(&tex2image, &output_file, format, matches.is_present("mipmaps"), &pixels)?;
+    for warning in warnings {
+        println!("Warning: {}", warning);
+    }
+    for file in written {
+        println!("Converted image saved to {}", file.display());
+    }
+    Ok(())
+}
+
+/// Saves a texture out as an image, along with each of its mipmap levels if `mipmaps` is set,
+/// and a sidecar with everything needed to turn it back into the same texture.
+///
+/// Returns every file that got written, and anything that'll stop the texture coming back
+/// exactly as it was.
+pub fn save_levels(tex2image: &tex2::DDTex2Image, output_file: &Path, format: ImageFormat, mipmaps: bool,
+                   pixels: &PixelOptions) -> Result<(Vec<PathBuf>, Vec<String>)> {
+    // Mipmaps that no filter makes out of the full-size image get saved too, or they'd be lost
+    let filter = if mipmaps || tex2image.mipmap_levels <= 1 { None } else { matching_filter(tex2image, pixels)? };
+    let mut warnings = vec![];
+    if !mipmaps && tex2image.mipmap_levels > 1 && filter.is_none() {
+        warnings.push("the mipmaps aren't what any filter makes out of the full-size image, so they've been saved too".to_string());
+    }
+    let mipmaps = mipmaps || (tex2image.mipmap_levels > 1 && filter.is_none());
+    let levels = if mipmaps { tex2image.mipmap_levels.max(1) } else { 1 };
+    let sidecar = texture_sidecar(tex2image, pixels, filter);
+    let mut written = vec![];
+    for i in 0..levels {
+        let level_file = if i == 0 { output_file.to_path_buf() } else { mipmap_file_name(output_file, i as usize) };
+        let mut image = tex2image.level(i)?.to_image();
+        pixels.decode(&mut image);
+        let alpha = if pixels.split_alpha {
+            let alpha = GrayImage::from_fn(image.width(), image.height(), |x, y| Luma([image.get_pixel(x, y)[3]]));
+            for pixel in image.pixels_mut() {
+                pixel[3] = 255;
+            }
+            Some(alpha)
+        } else {
+            None
+        };
+        save_image(&level_file, &image, format)
+            .chain_err(|| format!("Failed to save image to {}", level_file.display()))?;
+        if let Some(alpha) = alpha {
+            let alpha_file = alpha_file_name(&level_file);
+            save_dynamic(&alpha_file, DynamicImage::ImageLuma8(alpha), format)
+                .chain_err(|| format!("Failed to save alpha to {}", alpha_file.display()))?;
+            written.push(level_file);
+            written.push(alpha_file);
+        } else {
+            written.push(level_file);
+        }
+    }
+    sidecar.save_for(output_file)?;
+    written.push(Sidecar::path_for(output_file));
+    Ok((written, warnings))
+}
 
-    let mut _output_file = output_file.clone();
-    _output_file.set_extension("");
-    let filename = _output_file.file_name().unwrap_or("converted_dd_tex2".as_ref()).to_str().unwrap();
-    let ext = _output_file.extension().unwrap_or("png".as_ref()).to_str().unwrap();
+/// Notes down how a texture was stored, so it can be put back together byte for byte:
+/// the header as it was, anything after the pixels, how the mipmaps were made (by `filter`,
+/// or saved as files without one), and a checksum of the whole thing to check the result against.
+fn texture_sidecar(tex2image: &tex2::DDTex2Image, pixels: &PixelOptions, filter: Option<tex2::MipmapFilter>) -> Sidecar {
+    let mut sidecar = Sidecar::new();
+    let stored_levels = tex2image.mipmap_levels.max(1);
+    sidecar.set("mipmaps", if stored_levels == 1 {
+        "none".to_string()
+    } else if stored_levels == tex2::max_mipmap_count(tex2image.width, tex2image.height) {
+        "full".to_string()
+    } else {
+        stored_levels.to_string()
+    });
+    if stored_levels > 1 {
+        match filter {
+            Some(filter) => sidecar.set("mipmapfilter", filter.name()),
+            None => sidecar.set("mipmapfiles", "yes")
+        }
+    }
+    if pixels.order != tex2::ChannelOrder::Rgba {
+        sidecar.set("swizzle", pixels.order.name());
+    }
+    if pixels.premultiplied {
+        sidecar.set("premultiplied", "yes");
+    }
+    if pixels.split_alpha {
+        sidecar.set("splitalpha", "yes");
+    }
+    sidecar.set("originalwidth", tex2image.width.to_string());
+    sidecar.set("originalheight", tex2image.height.to_string());
+    sidecar.set("originalmipmaps", tex2image.mipmap_levels.to_string());
+    if !tex2image.trailing.is_empty() {
+        sidecar.set("trailing", to_hex(&tex2image.trailing));
+    }
+    sidecar.set("checksum", format!("{:016x}", tex2image.checksum()));
+    sidecar
+}
 
-    for i in 0..(max_mipmap_levels) {
-        tex2image.set_mipmap(i as u8);
-        if i != 0 {
-            output_file.set_file_name(format!("{}_{}.{}", filename, i, ext));
+/// Which filter, if any, builds the same mipmaps out of the full-size image as the texture has.
+fn matching_filter(tex2image: &tex2::DDTex2Image, pixels: &PixelOptions) -> Result<Option<tex2::MipmapFilter>> {
+    let mut base = tex2image.level(0)?.to_image();
+    pixels.decode(&mut base);
+    let (width, height) = (tex2image.width, tex2image.height);
+    for &filter in tex2::MipmapFilter::ALL.iter() {
+        let mut levels = vec![base.clone()];
+        while levels.len() < tex2image.mipmap_levels as usize {
+            let next = filter.downscale(levels.last().unwrap());
+            levels.push(next);
         }
-        match save_to_png(output_file.clone(), &tex2image) {
-            Ok(_) => {
-                println!("Converted image saved to {}", output_file.display());
-            },
-            Err(e) => {
-                println!("Error saving image to file {}", output_file.display());
-                println!("{:?}", e);
-                exit(1);
+        let mut rebuilt = tex2::DDTex2Image::from_levels(&levels)?;
+        pixels.encode(&mut rebuilt.pixels);
+        let base_length = width as usize * height as usize * 4;
+        if rebuilt.pixels[base_length..] == tex2image.pixels[base_length..] {
+            return Ok(Some(filter));
+        }
+    }
+    Ok(None)
+}
+
+/// Puts back what `texture_sidecar` noted down about the original texture and checks the
+/// result against it. Returns how it compares to the original, if the sidecar has a checksum.
+pub fn restore_original(tex2image: &mut tex2::DDTex2Image, sidecar: &Sidecar) -> Result<Option<String>> {
+    if sidecar.get("trailing").is_some() {
+        tex2image.trailing = original_trailing(sidecar)?;
+    }
+    // Some textures say they have 0 levels rather than 1
+    if sidecar.get("originalmipmaps") == Some("0") && tex2image.mipmap_levels == 1 {
+        tex2image.mipmap_levels = 0;
+    }
+    let checksum = match sidecar.get("checksum") {
+        Some(checksum) => u64::from_str_radix(checksum, 16)
+            .chain_err(|| format!("Sidecar has checksum = {}, which isn't hex", checksum))?,
+        None => return Ok(None)
+    };
+    let original_size = sidecar.get("originalwidth").and_then(|w| w.parse::<u32>().ok())
+        .zip(sidecar.get("originalheight").and_then(|h| h.parse::<u32>().ok()));
+    Ok(Some(match original_size {
+        _ if tex2image.checksum() == checksum => "identical to the original texture".to_string(),
+        Some((width, height)) if (width, height) != (tex2image.width, tex2image.height) =>
+            format!("{}x{}, but the original texture was {}x{}", tex2image.width, tex2image.height, width, height),
+        _ => "differs from the original texture (edited, or the mipmaps came out differently)".to_string()
+    }))
+}
+
+/// The bytes that came after the original texture's pixels, going by `trailing = ...` in its sidecar.
+pub fn original_trailing(sidecar: &Sidecar) -> Result<Vec<u8>> {
+    match sidecar.get("trailing") {
+        Some(trailing) => from_hex(trailing)
+            .ok_or_else(|| format!("Sidecar has trailing = {}, which isn't hex", trailing).into()),
+        None => Ok(vec![])
+    }
+}
+
+/// Converts every texture in a directory (and everything under it) or in an archive,
+/// writing the images out to a directory that's laid out the same way.
+fn batch(matches: &ArgMatches, input: &Path) -> Result<()> {
+    let extension = matches.value_of("format").unwrap_or("png");
+    let options = BatchOptions {
+        format: image_format(extension).unwrap(),
+        mipmaps: matches.is_present("mipmaps"),
+        pixels: PixelOptions::from_matches(matches, None)?
+    };
+    let output_dir = match matches.value_of("OUTFILE") {
+        Some(dir) => PathBuf::from(dir),
+        None => {
+            let mut name = input.file_name().unwrap_or_default().to_os_string();
+            name.push("_images");
+            input.with_file_name(name)
+        }
+    };
+    let jobs = jobs::job_count(matches)?;
+
+    let archive = if input.is_dir() { None } else { Some(MappedArchive::open(input)?) };
+    let mut plan = vec![];
+    match archive {
+        None => collect_textures(input, &output_dir, extension, &mut plan)?,
+        Some(ref archive) => {
+            for (entry, folder) in archive.entries().into_iter().zip(archive.entry_folders()) {
+                if entry.file_type == DDFiletype::Texture2 {
+                    let name = entry.filename_lossy().into_owned();
+                    let dir = match folder {
+                        Some(folder) => output_dir.join(folder),
+                        None => output_dir.clone()
+                    };
+                    plan.push(BatchJob {
+                        output_file: dir.join(format!("{}.{}", name, extension)),
+                        name,
+                        source: BatchSource::Entry(archive.data(&entry))
+                    });
+                }
+            }
+            for job in plan.iter() {
+                let parent = job.output_file.parent().unwrap();
+                fs::create_dir_all(parent)
+                    .chain_err(|| format!("Failed to create output directory {}", parent.display()))?;
             }
         }
     }
+    println!("## Converting {} texture{} from {}", plan.len(), if plan.len() == 1 {""} else {"s"}, input.display());
+    jobs::run_jobs(&plan, vec![(); jobs.min(plan.len())], "convert", |job| job.name.clone(), |_, job| {
+        match job.source {
+            BatchSource::File(ref path) => {
+                let mut data = vec![];
+                File::open(path).and_then(|mut f| f.read_to_end(&mut data))
+                    .chain_err(|| "Failed to read texture")?;
+                convert_texture(&data, job, &options)
+            },
+            BatchSource::Entry(data) => convert_texture(data, job, &options)
+        }
+    })
+}
+
+/// Finds every texture under `dir`, planning to put the images in the same place under `output_dir`.
+fn collect_textures(dir: &Path, output_dir: &Path, extension: &str, plan: &mut Vec<BatchJob>) -> Result<()> {
+    let mut paths = dir.read_dir()
+        .and_then(|iter| iter.map(|entry| entry.map(|e| e.path())).collect::<::std::io::Result<Vec<_>>>())
+        .chain_err(|| format!("Failed to read file list from directory {}", dir.display()))?;
+    paths.sort();
+    for path in paths {
+        let output_file = output_dir.join(path.file_name().unwrap());
+        if path.is_dir() {
+            collect_textures(&path, &output_file, extension, plan)?;
+        } else if path.extension().is_some_and(|ext| ext == DDFiletype::Texture2.extension().as_str()) {
+            fs::create_dir_all(output_dir)
+                .chain_err(|| format!("Failed to create output directory {}", output_dir.display()))?;
+            plan.push(BatchJob {
+                name: path.display().to_string(),
+                output_file: output_file.with_extension(extension),
+                source: BatchSource::File(path)
+            });
+        }
+    }
     Ok(())
 }
 
-pub fn read_tex2<P: AsRef<Path>>(file: P) -> io::Result<tex2::DDTex2Image> {
-    let f = File::open(file)?;
+/// Decodes one texture of a batch and saves it, returning the lines to print.
+fn convert_texture(data: &[u8], job: &BatchJob, options: &BatchOptions) -> Result<Vec<String>> {
+    let tex2image = tex2::DDTex2Image::read(data)?;
+    let (written, warnings) = save_levels(&tex2image, &job.output_file, options.format, options.mipmaps, &options.pixels)?;
+    Ok(warnings.iter().map(|warning| format!("Warning: {}: {}", job.name, warning))
+       .chain(written.iter().map(|file| format!("Converted {} to {}", job.name, file.display())))
+       .collect())
+}
+
+/// Whether `file` starts like an archive does.
+fn is_archive(file: &Path) -> Result<bool> {
+    let mut magic = vec![];
+    File::open(file).and_then(|f| f.take(8).read_to_end(&mut magic))
+        .chain_err(|| format!("Failed to read {}", file.display()))?;
+    Ok(magic == b":hx:rg:\x01")
+}
+
+pub fn read_tex2<P: AsRef<Path>>(file: P) -> Result<tex2::DDTex2Image> {
+    let f = File::open(file).chain_err(|| "Failed to open texture")?;
     let mut reader = BufReader::new(f);
     let mut buf: Vec<u8> = Vec::with_capacity(5000);
-    reader.read_to_end(&mut buf)?;
-    match tex2::tex2_image(buf.as_ref()) {
-        IResult::Done(_, tex2image) => {
-            return Ok(tex2image);
+    reader.read_to_end(&mut buf).chain_err(|| "Failed to read texture")?;
+    tex2::DDTex2Image::read(buf.as_ref())
+}
+
+/// Saves `img` as `format`, dropping the alpha channel for formats that can't hold it.
+pub fn save_image(output_file: &Path, img: &RgbaImage, format: ImageFormat) -> Result<()> {
+    save_dynamic(output_file, DynamicImage::ImageRgba8(img.clone()), format)
+}
+
+/// Saves an image of any colour type as `format`, converting it to something the format can hold if need be.
+pub fn save_dynamic(output_file: &Path, img: DynamicImage, format: ImageFormat) -> Result<()> {
+    let mut fout = BufWriter::new(File::create(output_file).chain_err(|| "Failed to open output image file")?);
+    let (width, height) = img.dimensions();
+    let saved = match format {
+        ImageFormat::Pnm => {
+            // Greyscale for .pgm, colour for everything else. Neither has alpha.
+            let ext = output_file.extension().map(|e| e.to_string_lossy().to_lowercase());
+            if ext.as_deref() == Some("pgm") {
+                DynamicImage::ImageLuma8(img.to_luma8())
+                    .write_to(&mut fout, ImageOutputFormat::Pnm(PnmSubtype::Graymap(SampleEncoding::Binary)))
+            } else {
+                DynamicImage::ImageRgb8(img.to_rgb8())
+                    .write_to(&mut fout, ImageOutputFormat::Pnm(PnmSubtype::Pixmap(SampleEncoding::Binary)))
+            }
         },
-        IResult::Error(err) => {
-            println!("Failed to read tex2 image");
-            println!("{}", err.description());
-            exit(1);
+        // No alpha in JPEG
+        ImageFormat::Jpeg if img.color().has_alpha() => DynamicImage::ImageRgb8(img.to_rgb8()).write_to(&mut fout, format),
+        // write_to doesn't do TIFF, so go straight to the encoder
+        ImageFormat::Tiff => TiffEncoder::new(&mut fout).write_image(img.as_bytes(), width, height, img.color()),
+        // Icons have to have alpha
+        ImageFormat::Ico if !img.color().has_alpha() => DynamicImage::ImageRgba8(img.to_rgba8()).write_to(&mut fout, format),
+        _ => img.write_to(&mut fout, format)
+    };
+    saved.chain_err(|| "Failed to save output image")?;
+    fout.flush().chain_err(|| "Failed to save output image")?;
+    Ok(())
+}
+
+/// Looks up a format by name or extension.
+pub fn image_format(name: &str) -> Option<ImageFormat> {
+    let name = name.to_lowercase();
+    IMAGE_FORMATS.iter().find(|(n, _)| *n == name).map(|(_, format)| *format)
+}
+
+/// Works out which format to save `file` as from its extension.
+pub fn output_format(file: &Path) -> Result<ImageFormat> {
+    let ext = file.extension().map(|e| e.to_string_lossy()).unwrap_or_default();
+    image_format(&ext).ok_or_else(|| format!(
+        "Don't know what format to save {} as, use --format to pick one", file.display()).into())
+}
+
+/// The other direction: turns an ordinary image into a tex2 file.
+fn encode(matches: &ArgMatches) -> Result<()> {
+    let input_file = PathBuf::from(matches.value_of("FILE").unwrap());
+    let output_file = if matches.is_present("OUTFILE") {
+        PathBuf::from(matches.value_of("OUTFILE").unwrap())
+    } else {
+        input_file.with_extension(DDFiletype::Texture2.extension())
+    };
+
+    let sidecar = Sidecar::load_for(&input_file)?;
+    let original = match matches.value_of("original") {
+        Some(texture) => Some(OriginalTexture::from_texture(Path::new(texture))?),
+        None => None
+    };
+    if matches.is_present("matchoriginal") && original.is_none() && OriginalTexture::from_sidecar(sidecar.as_ref())?.is_none() {
+        return Err(format!("{} has no sidecar saying what texture it's replacing, use --original to pick one",
+                           input_file.display()).into());
+    }
+    let options = EncodeOptions {
+        format: matches.value_of("format").map(|f| image_format(f).unwrap()),
+        alpha_file: matches.value_of("alpha").map(PathBuf::from),
+        original,
+        match_original: if matches.is_present("matchoriginal") || matches.is_present("original") {
+            Some(ResizeMode::from_name(matches.value_of("resize").unwrap_or("fit")).unwrap())
+        } else {
+            None
         },
-        IResult::Incomplete(needed) => {
-            println!("need {:?} more bytes", needed);
-            exit(1);
+        full_mipmaps: matches.is_present("mipmaps"),
+        default_mipmaps: "none",
+        filter: matches.value_of("filter").map(|filter| tex2::MipmapFilter::from_name(filter).unwrap()),
+        mipmap_files: matches.is_present("mipmapfiles"),
+        pixels: PixelOptions::from_matches(matches, sidecar.as_ref())?
+    };
+    let (tex2image, messages) = encode_image(&input_file, sidecar.as_ref(), &options)?;
+    for message in messages {
+        println!("{}", message);
+    }
+
+    let mut fout = BufWriter::new(File::create(&output_file)
+        .chain_err(|| format!("Failed to open output file {}", output_file.display()))?);
+    tex2image.save(&mut fout)
+        .and_then(|_| fout.flush())
+        .chain_err(|| format!("Failed to save texture to {}", output_file.display()))?;
+    println!("Converted texture saved to {}", output_file.display());
+    Ok(())
+}
+
+/// How to turn an image into a texture, beyond what its sidecar says.
+pub struct EncodeOptions {
+    /// Format the image is in, instead of going by its contents
+    pub format: Option<ImageFormat>,
+    /// Greyscale image to take alpha from, instead of the image itself
+    pub alpha_file: Option<PathBuf>,
+    /// Texture being replaced, instead of the one the sidecar says the image came from
+    pub original: Option<OriginalTexture>,
+    /// How to resize the image to the size of the texture it's replacing, if it should be
+    pub match_original: Option<ResizeMode>,
+    /// Build every level down to 1x1, never mind the original texture or the sidecar
+    pub full_mipmaps: bool,
+    /// What to go by when the sidecar doesn't have `mipmaps = ...`
+    pub default_mipmaps: &'static str,
+    /// How to shrink each mipmap level, instead of what the sidecar says
+    pub filter: Option<tex2::MipmapFilter>,
+    /// Use hand-drawn levels where they exist, even if the sidecar doesn't say to
+    pub mipmap_files: bool,
+    pub pixels: PixelOptions
+}
+
+impl EncodeOptions {
+    /// The texture an image has to be resized to match, if any.
+    fn original(&self, sidecar: Option<&Sidecar>) -> Result<Option<OriginalTexture>> {
+        match (self.match_original, self.original) {
+            (None, _) => Ok(None),
+            (Some(_), Some(original)) => Ok(Some(original)),
+            (Some(_), None) => OriginalTexture::from_sidecar(sidecar)
         }
     }
 }
 
-pub fn save_to_png<P: AsRef<Path>>(output_file: P, tex2img: &tex2::DDTex2Image) -> Result<()> {
-    let mut img = ImageBuffer::new(tex2img.cur_width(), tex2img.cur_height());
-    img.copy_from(tex2img, 0, 0);
+/// The size and number of levels of the texture `encode_image` makes out of an image that's
+/// `width`x`height`, which is the size of the texture it's replacing if it's being matched to one.
+fn texture_shape(file: &Path, sidecar: Option<&Sidecar>, options: &EncodeOptions,
+                 width: u32, height: u32) -> Result<(u32, u32, usize)> {
+    let original = options.original(sidecar)
+        .chain_err(|| format!("Failed to read {}'s sidecar", file.display()))?;
+    let (width, height) = original.map_or((width, height), |original| (original.width, original.height));
+    // The command line wins over the original texture, which wins over the sidecar
+    let count = if options.full_mipmaps {
+        tex2::max_mipmap_count(width, height) as usize
+    } else if let Some(original) = original {
+        original.mipmaps as usize
+    } else {
+        sidecar_mipmaps(file, sidecar, width, height, options.default_mipmaps)?
+    };
+    Ok((width, height, count))
+}
+
+/// The size and number of levels of the texture `encode_image` will make, without reading the whole image.
+pub fn planned_texture(file: &Path, sidecar: Option<&Sidecar>, options: &EncodeOptions) -> Result<(u32, u32, usize)> {
+    let (width, height) = image_dimensions(file)?;
+    texture_shape(file, sidecar, options, width, height)
+}
+
+/// Turns an image into a texture, along with anything the image's sidecar says about it.
+/// Returns the texture and the lines to print about how it went.
+pub fn encode_image(file: &Path, sidecar: Option<&Sidecar>, options: &EncodeOptions) -> Result<(tex2::DDTex2Image, Vec<String>)> {
+    let mut messages = vec![];
+    let split_alpha = sidecar.is_some_and(splits_alpha);
+    let mut img = match options.alpha_file {
+        Some(ref alpha_file) => {
+            let mut img = open_image(file, options.format)?;
+            merge_alpha(file, &mut img, alpha_file)?;
+            img
+        },
+        None => open_split_image(file, options.format, split_alpha)?
+    };
+    let (width, height, count) = texture_shape(file, sidecar, options, img.width(), img.height())?;
+    let resized = img.dimensions() != (width, height);
+    if resized {
+        messages.push(format!("{}: resizing from {}x{} to {}x{} to match the original texture",
+                              file.display(), img.width(), img.height(), width, height));
+        img = options.match_original.unwrap().resize(&img, width, height);
+    }
+    if !width.is_power_of_two() || !height.is_power_of_two() {
+        messages.push(format!("Warning: {} is {}x{}, but the game's textures are all powers of two",
+                              file.display(), width, height));
+    }
 
-    let ref mut fout = File::create(output_file).chain_err(|| "Failed to open output image file")?;
-    image::ImageRgba8(img).save(fout, image::PNG).chain_err(|| "Failed to save output image")?;
+    let filter = match options.filter {
+        Some(filter) => filter,
+        None => sidecar_filter(file, sidecar)?
+    };
+    // Hand-drawn levels were drawn for the old size, so they won't fit any more
+    let use_files = !resized && (options.mipmap_files || sidecar.is_some_and(uses_mipmap_files));
+    let (levels, level_files) = build_levels(file, img, count, filter, use_files, split_alpha, options.format)?;
+    for (i, level_file) in level_files {
+        messages.push(format!("{}: using {} for mipmap level {}", file.display(), level_file.display(), i));
+    }
+    let mut tex2image = tex2::DDTex2Image::from_levels(&levels)
+        .chain_err(|| format!("Failed to convert {} to a texture", file.display()))?;
+    options.pixels.encode(&mut tex2image.pixels);
+    if let Some(sidecar) = sidecar {
+        if let Some(comparison) = restore_original(&mut tex2image, sidecar)
+            .chain_err(|| format!("Failed to convert {} to a texture", file.display()))? {
+            messages.push(format!("{}: {}", file.display(), comparison));
+        }
+    }
+    Ok((tex2image, messages))
+}
+
+/// How many levels `mipmaps = ...` in an image's sidecar asks for: `full`, `none`,
+/// or a number of levels counting the full-size image. Goes by `default` if it doesn't say.
+pub fn sidecar_mipmaps(file: &Path, sidecar: Option<&Sidecar>, width: u32, height: u32, default: &str) -> Result<usize> {
+    match sidecar.and_then(|s| s.get("mipmaps")).unwrap_or(default) {
+        "full" => Ok(tex2::max_mipmap_count(width, height) as usize),
+        "none" => Ok(1),
+        n => match n.parse::<u8>() {
+            Ok(n) if n >= 1 && n <= tex2::max_mipmap_count(width, height) => Ok(n as usize),
+            _ => Err(format!("{}: sidecar has mipmaps = {}, but it should be full, none, or 1 to {}",
+                             file.display(), n, tex2::max_mipmap_count(width, height)).into())
+        }
+    }
+}
+
+/// The filter `mipmapfilter = ...` in an image's sidecar asks for, box if it doesn't say.
+pub fn sidecar_filter(file: &Path, sidecar: Option<&Sidecar>) -> Result<tex2::MipmapFilter> {
+    let name = sidecar.and_then(|s| s.get("mipmapfilter")).unwrap_or("box");
+    tex2::MipmapFilter::from_name(name)
+        .ok_or_else(|| format!("{}: sidecar has unrecognized mipmapfilter {}", file.display(), name).into())
+}
+
+/// Whether an image's sidecar says its alpha was saved as an image of its own.
+pub fn splits_alpha(sidecar: &Sidecar) -> bool {
+    is_yes(sidecar.get("splitalpha"))
+}
+
+/// Whether an image's sidecar says to use hand-drawn mipmap level files.
+pub fn uses_mipmap_files(sidecar: &Sidecar) -> bool {
+    is_yes(sidecar.get("mipmapfiles"))
+}
+
+fn is_yes(value: Option<&str>) -> bool {
+    matches!(value, Some("yes") | Some("true"))
+}
+
+/// Mipmap levels that were read from files rather than generated, by level number.
+pub type LevelFiles = Vec<(usize, PathBuf)>;
+
+/// Builds `count` levels for a texture out of `img`, shrinking each one with `filter`.
+///
+/// With `use_files`, hand-drawn levels sitting next to `input_file` are used where they exist,
+/// named like the ones imgconv --mipmaps exports. Those get returned along with the levels.
+/// With `split_alpha`, each of them has its alpha in an image of its own too.
+pub fn build_levels(input_file: &Path, img: RgbaImage, count: usize, filter: tex2::MipmapFilter,
+                    use_files: bool, split_alpha: bool, format: Option<ImageFormat>) -> Result<(Vec<RgbaImage>, LevelFiles)> {
+    let mut levels = vec![img];
+    let mut level_files = vec![];
+    for i in 1..count {
+        let level_file = mipmap_file_name(input_file, i);
+        if use_files && level_file.is_file() {
+            levels.push(open_split_image(&level_file, format, split_alpha)?);
+            level_files.push((i, level_file));
+        } else {
+            let next = filter.downscale(levels.last().unwrap());
+            levels.push(next);
+        }
+    }
+    Ok((levels, level_files))
+}
+
+/// Opens an image, going by its contents (or `format`, if given) rather than just its extension.
+pub fn open_image(file: &Path, format: Option<ImageFormat>) -> Result<RgbaImage> {
+    Ok(open_dynamic(file, format)?.to_rgba8())
+}
+
+/// Opens an image, and with `split_alpha` takes its alpha from the greyscale image
+/// `--split-alpha` saved next to it.
+pub fn open_split_image(file: &Path, format: Option<ImageFormat>, split_alpha: bool) -> Result<RgbaImage> {
+    let mut img = open_image(file, format)?;
+    if split_alpha {
+        let alpha_file = alpha_file_name(file);
+        if !alpha_file.is_file() {
+            return Err(format!("{}'s alpha was saved separately, but {} isn't there", file.display(), alpha_file.display()).into());
+        }
+        merge_alpha(file, &mut img, &alpha_file)?;
+    }
+    Ok(img)
+}
+
+/// Replaces `img`'s alpha with the greyscale image in `alpha_file`, which has to be the same size.
+fn merge_alpha(file: &Path, img: &mut RgbaImage, alpha_file: &Path) -> Result<()> {
+    let alpha = open_dynamic(alpha_file, None)?.to_luma8();
+    if alpha.dimensions() != img.dimensions() {
+        return Err(format!("{} is {}x{}, but {} is {}x{}", alpha_file.display(), alpha.width(), alpha.height(),
+                           file.display(), img.width(), img.height()).into());
+    }
+    for (pixel, alpha) in img.pixels_mut().zip(alpha.pixels()) {
+        pixel[3] = alpha[0];
+    }
     Ok(())
-}
\ No newline at end of file
+}
+
+/// How big an image is, without reading the whole thing.
+pub fn image_dimensions(file: &Path) -> Result<(u32, u32)> {
+    ImageReader::open(file)
+        .and_then(|reader| reader.with_guessed_format())
+        .chain_err(|| format!("Failed to open image {}", file.display()))?
+        .into_dimensions()
+        .chain_err(|| format!("Failed to read image {}", file.display()))
+}
+
+/// Opens an image without converting it to RGBA.
+pub fn open_dynamic(file: &Path, format: Option<ImageFormat>) -> Result<DynamicImage> {
+    let mut reader = ImageReader::open(file)
+        .chain_err(|| format!("Failed to open image {}", file.display()))?;
+    match format {
+        Some(format) => reader.set_format(format),
+        None => reader = reader.with_guessed_format()
+            .chain_err(|| format!("Failed to read image {}", file.display()))?
+    }
+    reader.decode().chain_err(|| format!("Failed to read image {}", file.display()))
+}
+
+/// Where the alpha channel of `file` goes when it's split out: `name.png` has it in `name_alpha.png`.
+pub fn alpha_file_name(file: &Path) -> PathBuf {
+    let stem = file.file_stem().unwrap_or_default().to_string_lossy();
+    match file.extension() {
+        Some(ext) => file.with_file_name(format!("{}_alpha.{}", stem, ext.to_string_lossy())),
+        None => file.with_file_name(format!("{}_alpha", stem))
+    }
+}
+
+/// Where mipmap level `level` of `file` goes: `name.png` has its levels in `name_1.png`, `name_2.png`, etc.
+pub fn mipmap_file_name(file: &Path, level: usize) -> PathBuf {
+    let stem = file.file_stem().unwrap_or_default().to_string_lossy();
+    match file.extension() {
+        Some(ext) => file.with_file_name(format!("{}_{}.{}", stem, level, ext.to_string_lossy())),
+        None => file.with_file_name(format!("{}_{}", stem, level))
+    }
+}
@@ -0,0 +1,130 @@
+use clap::ArgMatches;
+use image::{ImageBuffer, Rgba, RgbaImage};
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use super::super::errors::*;
+use super::imgconv;
+
+/// How the differences between two levels add up.
+struct LevelDiff {
+    /// Biggest difference in any one channel of any one pixel
+    max_error: u8,
+    /// Average difference per channel, over every channel of every pixel
+    mean_error: f64,
+    /// How many pixels have any difference at all
+    differing: u64
+}
+
+/// One side of the comparison: every level of a texture, or just the one for an ordinary image.
+struct DiffInput {
+    levels: Vec<RgbaImage>,
+    is_texture: bool
+}
+
+pub fn execute(matches: &ArgMatches) -> Result<()> {
+    let tolerance = match matches.value_of("tolerance") {
+        Some(tolerance) => tolerance.parse::<u8>()
+            .chain_err(|| format!("Invalid tolerance {}, it should be 0 to 255", tolerance))?,
+        None => 0
+    };
+    let (a_path, b_path) = (Path::new(matches.value_of("A").unwrap()), Path::new(matches.value_of("B").unwrap()));
+    let a = read_input(a_path)?;
+    let b = read_input(b_path)?;
+
+    let mut problems = vec![];
+    let (a_base, b_base) = (&a.levels[0], &b.levels[0]);
+    if a_base.dimensions() != b_base.dimensions() {
+        return Err(format!("{} is {}x{}, but {} is {}x{}",
+                           a_path.display(), a_base.width(), a_base.height(),
+                           b_path.display(), b_base.width(), b_base.height()).into());
+    }
+    // An ordinary image only has the one level, so only hold it against two textures
+    if a.is_texture && b.is_texture && a.levels.len() != b.levels.len() {
+        println!("Mipmap levels differ: {} has {}, {} has {}",
+                 a_path.display(), a.levels.len(), b_path.display(), b.levels.len());
+        problems.push("mipmap level counts differ".to_string());
+    }
+
+    for (n, (a_level, b_level)) in a.levels.iter().zip(b.levels.iter()).enumerate() {
+        let diff = diff_level(a_level, b_level);
+        let total = a_level.width() as u64 * a_level.height() as u64;
+        println!("level {} ({}x{}): max error {}, mean error {:.4}, {} of {} pixel{} differ{}",
+                 n, a_level.width(), a_level.height(),
+                 diff.max_error, diff.mean_error,
+                 diff.differing, total, if total == 1 {""} else {"s"},
+                 if diff.differing == 1 {"s"} else {""});
+        if diff.max_error > tolerance {
+            problems.push(format!("level {} has a max error of {}", n, diff.max_error));
+        }
+    }
+
+    if let Some(output) = matches.value_of("output") {
+        let output = Path::new(output);
+        let format = imgconv::output_format(output)?;
+        imgconv::save_image(output, &highlight(a_base, b_base), format)
+            .chain_err(|| format!("Failed to save difference image to {}", output.display()))?;
+        println!("Saved difference image to {}", output.display());
+    }
+
+    if !problems.is_empty() {
+        return Err(format!("Images differ (tolerance {}): {}", tolerance, problems.join(", ")).into());
+    }
+    println!("Images match (tolerance {})", tolerance);
+    Ok(())
+}
+
+/// Reads a texture, or any image imgconv can read.
+fn read_input(path: &Path) -> Result<DiffInput> {
+    let mut magic = vec![];
+    File::open(path).and_then(|f| f.take(2).read_to_end(&mut magic))
+        .chain_err(|| format!("Failed to read {}", path.display()))?;
+    if magic == b"\x11\x40" {
+        let texture = imgconv::read_tex2(path).chain_err(|| format!("Failed to read texture {}", path.display()))?;
+        let levels = texture.levels()?.iter().map(|level| level.to_image()).collect();
+        Ok(DiffInput { levels, is_texture: true })
+    } else {
+        Ok(DiffInput { levels: vec![imgconv::open_image(path, None)?], is_texture: false })
+    }
+}
+
+fn diff_level(a: &RgbaImage, b: &RgbaImage) -> LevelDiff {
+    let mut max_error = 0;
+    let mut total_error = 0u64;
+    let mut differing = 0;
+    for (a_pixel, b_pixel) in a.pixels().zip(b.pixels()) {
+        let pixel_error = channel_errors(a_pixel, b_pixel);
+        if pixel_error.iter().any(|&e| e != 0) {
+            differing += 1;
+        }
+        max_error = pixel_error.iter().fold(max_error, |max, &e| max.max(e));
+        total_error += pixel_error.iter().map(|&e| e as u64).sum::<u64>();
+    }
+    let channels = a.width() as u64 * a.height() as u64 * 4;
+    LevelDiff { max_error, mean_error: total_error as f64 / channels as f64, differing }
+}
+
+fn channel_errors(a: &Rgba<u8>, b: &Rgba<u8>) -> [u8; 4] {
+    let mut errors = [0; 4];
+    for (c, error) in errors.iter_mut().enumerate() {
+        *error = (a[c] as i16 - b[c] as i16).unsigned_abs() as u8;
+    }
+    errors
+}
+
+/// A dimmed greyscale copy of `a`, with every pixel that differs from `b` in red.
+/// The redder the pixel, the bigger the difference, but even the smallest ones stand out.
+fn highlight(a: &RgbaImage, b: &RgbaImage) -> RgbaImage {
+    ImageBuffer::from_fn(a.width(), a.height(), |x, y| {
+        let (a_pixel, b_pixel) = (a.get_pixel(x, y), b.get_pixel(x, y));
+        let error = *channel_errors(a_pixel, b_pixel).iter().max().unwrap();
+        if error == 0 {
+            let grey = ((a_pixel[0] as u32 * 299 + a_pixel[1] as u32 * 587 + a_pixel[2] as u32 * 114) / 1000) as u8;
+            Rgba([grey / 3, grey / 3, grey / 3, 255])
+        } else {
+            Rgba([128 + error / 2, 0, 0, 255])
+        }
+    })
+}
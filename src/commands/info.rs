@@ -8,15 +8,17 @@ use nom::IResult;
 use bytesize::ByteSize;
 
 use super::super::parser;
+use super::super::tex1;
 use super::super::tex2;
 use super::super::errors::*;
 
 #[derive(Debug, PartialEq)]
 enum GuessedFormat {
     DDArchive,
+    Texture1,
     Texture2,
     GLSLShader,
-    HRTF,
+    Hrtf,
     Unknown
 }
 
@@ -31,13 +33,16 @@ pub fn execute(matches: &ArgMatches) -> Result<()> {
         DDArchive => {
             archive_info(matches, &mut reader)?;
         },
+        Texture1 => {
+            texture1_info(matches, &mut reader)?;
+        },
         Texture2 => {
             texture_info(matches, &mut reader)?;
         },
         GLSLShader => {
             glsl_info(matches, &mut reader)?;
         },
-        HRTF => {
+        Hrtf => {
             println!("{}: HRTF/mhr file (generic OpenAL asset)", matches.value_of("FILE").unwrap());
             println!("See the following links for more info:");
             println!("- https://github.com/kcat/openal-soft/blob/master/utils/makehrtf.c");
@@ -50,13 +55,14 @@ pub fn execute(matches: &ArgMatches) -> Result<()> {
     Ok(())
 }
 
-fn guess_format<R: Read + Seek>(mut reader: &mut R) -> Result<GuessedFormat> {
+fn guess_format<R: Read + Seek>(reader: &mut R) -> Result<GuessedFormat> {
     use self::GuessedFormat::*;
 
     // Read out the first 40 bytes of the file
     // And use that to take guesses at it
     let mut buf = vec![0u8; 40];
     reader.read_exact(&mut buf[..]).chain_err(|| "Failed to read file")?;
+    let length = reader.seek(SeekFrom::End(0)).chain_err(|| "Failed to find file length")?;
     // restart the position for whatever wants to read this next
     reader.seek(SeekFrom::Start(0)).chain_err(|| "Failed to reset file read position")?;
 
@@ -70,24 +76,37 @@ fn guess_format<R: Read + Seek>(mut reader: &mut R) -> Result<GuessedFormat> {
         return Ok(Texture2);
     }
 
+    // tex1 has no magic number, so this is only a guess
+    let could_be_tex1 = match tex1::tex1_header(&buf) {
+        IResult::Done(_, header) => header.fits(length),
+        _ => false
+    };
+
     // GLSL shader file?
-    if let IResult::Done(_, _) = parser::glsl_file_header(&buf) {
-        return Ok(GLSLShader);
+    // Its header's lengths are about as loose as tex1's, so it only wins if they add up
+    if let IResult::Done(_, (name, vert_len, frag_len)) = parser::glsl_file_header(&buf) {
+        if !could_be_tex1 || 12 + name.len() as u64 + vert_len as u64 + frag_len as u64 == length {
+            return Ok(GLSLShader);
+        }
     }
 
     // OpenAL default-44100.mhr and default-48000.mhr files
     // in the root of the game dir
     if buf.starts_with(b"MinPHR01") {
-        return Ok(HRTF);
+        return Ok(Hrtf);
     }
 
+    // Texture1 file? This goes last, since all it checks is that the counts at the start fit in the file
+    if could_be_tex1 {
+        return Ok(Texture1);
+    }
 
     Ok(Unknown)
 }
 
 fn archive_info<R: Read>(matches: &ArgMatches, mut reader: &mut R) -> Result<()> {
     if let Ok((header, files)) = parser::read_header(&mut reader).chain_err(|| "Failed to parse archive header")? {
-        let totalsize = files.iter().fold(0, |acc, ref x| acc + x.size) as usize;
+        let totalsize = files.iter().fold(0, |acc, x| acc + x.size) as usize;
         println!("{filename}: dd archive, {header} byte header, {count} file{countplural}, totaling {total}",
                  filename=matches.value_of("FILE").unwrap(),
                  count=files.len(),
@@ -131,14 +150,15 @@ fn archive_info<R: Read>(matches: &ArgMatches, mut reader: &mut R) -> Result<()>
     Ok(())
 }
 
-fn texture_info<R: Read>(matches: &ArgMatches, mut reader: &mut R) -> Result<()> {
+fn texture_info<R: Read>(matches: &ArgMatches, reader: &mut R) -> Result<()> {
     let mut buf = vec![0u8; 11];
     reader.read_exact(&mut buf[..]).chain_err(|| "Unable to read texture file header")?;
     if let IResult::Done(_, info) = tex2::tex2_header(&buf) {
+        // The header has height first, but everything else says width first
         println!("{}: texture2, {}x{}, {} mipmap level{}",
                  matches.value_of("FILE").unwrap(),
-                 info.0,
                  info.1,
+                 info.0,
                  info.2,
                  if info.2 == 1 {""} else {"s"}
         );
@@ -148,7 +168,64 @@ fn texture_info<R: Read>(matches: &ArgMatches, mut reader: &mut R) -> Result<()>
     Ok(())
 }
 
-fn glsl_info<R: Read>(matches: &ArgMatches, mut reader: &mut R) -> Result<()> {
+fn texture1_info<R: Read>(matches: &ArgMatches, reader: &mut R) -> Result<()> {
+    let mut buf = vec![];
+    reader.read_to_end(&mut buf).chain_err(|| "Unable to read texture1 file")?;
+    let header = match tex1::tex1_header(&buf) {
+        IResult::Done(_, header) => header,
+        _ => {
+            println!("A very strange error occurred");
+            return Ok(());
+        }
+    };
+    println!("{}: texture1 (probably a model), {} bytes",
+             matches.value_of("FILE").unwrap(),
+             buf.len()
+    );
+    println!("header: {} indices?, {} vertices?, unknown u16 {} ({:#06x})",
+             header.index_count, header.vertex_count, header.unknown, header.unknown);
+
+    let body = &buf[tex1::TEX1_HEADER_LENGTH..];
+    let expected = header.expected_length();
+    println!("as {}-byte vertices then u32 indices: {} bytes expected, {} left over at the end",
+             tex1::TEX1_VERTEX_SIZE, expected, buf.len() as u64 - expected);
+    // Only the vertices are records, so look at just them
+    let vertex_end = header.vertex_count as usize * tex1::TEX1_VERTEX_SIZE;
+    let records = &body[..vertex_end];
+    let indices = tex1::index_stats(&body[vertex_end..vertex_end + header.index_count as usize * 4], header.vertex_count);
+    println!("indices: {}, biggest {}, {} past the last vertex{}",
+             indices.count, indices.max, indices.out_of_range,
+             if indices.count.is_multiple_of(3) { ", a whole number of triangles" } else { "" });
+
+    let guesses = tex1::guess_strides(records, 64);
+    println!("stride guesses for the vertices (how often a float's exponent repeats that far on):");
+    for guess in guesses.iter().take(5) {
+        println!("- {:2} bytes: {:5.1}%", guess.stride, guess.score * 100.0);
+    }
+    let stride = match tex1::best_stride(&guesses) {
+        Some(stride) => stride,
+        None => return Ok(())
+    };
+    println!("columns at a {} byte stride:", stride);
+    for column in tex1::column_stats(records, stride) {
+        let range = match column.float_range {
+            Some((min, max, mean)) => format!(", {} to {}, mean {:.4}", min, max, mean),
+            None => String::new()
+        };
+        println!("- +{:<2}: {:5.1}% floats{}; {:5.1}% small ints",
+                 column.offset, column.floats * 100.0, range, column.small_ints * 100.0);
+    }
+    let repeats = tex1::repeated_records(records, stride);
+    println!("repeated records at a {} byte stride: {} records, {} distinct{}",
+             stride, repeats.records, repeats.distinct,
+             match repeats.most_common {
+                 Some((index, count)) => format!(", record {} shows up most ({} times)", index, count),
+                 None => String::new()
+             });
+    Ok(())
+}
+
+fn glsl_info<R: Read>(matches: &ArgMatches, reader: &mut R) -> Result<()> {
     let mut buf = vec![];
     reader.read_to_end(&mut buf).chain_err(|| "unable to read out GLSL file")?;
     if let IResult::Done(_, info) = parser::glsl_file(&buf) {
@@ -0,0 +1,83 @@
+use clap::ArgMatches;
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+use super::super::errors::*;
+
+/// Reads `-j`/`--jobs`, which defaults to 1.
+pub fn job_count(matches: &ArgMatches) -> Result<usize> {
+    match matches.value_of("jobs") {
+        Some(jobs) => jobs.parse::<usize>().ok().filter(|&j| j > 0)
+            .ok_or_else(|| format!("Invalid job count {}", jobs).into()),
+        None => Ok(1)
+    }
+}
+
+/// Runs `work` on every job, with one thread for each of `workers`.
+///
+/// Each thread hangs on to its own worker (a file handle, say) and passes it to `work` along with
+/// each job it picks up. The lines `work` returns are printed in the same order as `jobs`,
+/// however the threads happen to finish. Failures are printed as they come up, then summed up
+/// at the end, `verb` and `describe` saying what was being done to what.
+pub fn run_jobs<J, W, F, D>(jobs: &[J], workers: Vec<W>, verb: &str, describe: D, work: F) -> Result<()>
+    where J: Sync, W: Send, F: Fn(&mut W, &J) -> Result<Vec<String>> + Sync, D: Fn(&J) -> String
+{
+    // Results come back tagged with their index so they can be reported in order
+    let next_job = AtomicUsize::new(0);
+    let (tx, rx) = mpsc::channel();
+    let mut failures: Vec<(usize, Error)> = vec![];
+    thread::scope(|scope| {
+        for mut worker in workers {
+            let tx = tx.clone();
+            let (work, next_job) = (&work, &next_job);
+            scope.spawn(move || {
+                loop {
+                    let i = next_job.fetch_add(1, Ordering::SeqCst);
+                    if i >= jobs.len() { break; }
+                    let result = work(&mut worker, &jobs[i]);
+                    if tx.send((i, result)).is_err() { break; }
+                }
+            });
+        }
+        drop(tx);
+
+        // Print everything in order, holding on to results that arrive early
+        let mut pending = BTreeMap::new();
+        let mut next_print = 0;
+        for (i, result) in rx {
+            pending.insert(i, result);
+            while let Some(result) = pending.remove(&next_print) {
+                match result {
+                    Ok(log) => {
+                        for line in log { println!("{}", line); }
+                    },
+                    Err(e) => {
+                        println!("Failed to {} {}: {}", verb, describe(&jobs[next_print]), e);
+                        failures.push((next_print, e));
+                    }
+                }
+                next_print += 1;
+            }
+        }
+    });
+
+    if !failures.is_empty() {
+        println!("## {} of {} file{} failed to {}:",
+                 failures.len(),
+                 jobs.len(),
+                 if jobs.len() == 1 {""} else {"s"},
+                 verb);
+        for &(i, ref e) in failures.iter() {
+            println!("- {}: {}", describe(&jobs[i]), e);
+            for cause in e.iter().skip(1) {
+                println!("    caused by: {}", cause);
+            }
+        }
+        return Err(format!("Failed to {} {} file{}", verb, failures.len(),
+                           if failures.len() == 1 {""} else {"s"}).into());
+    }
+    Ok(())
+}
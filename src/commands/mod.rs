@@ -1,4 +1,9 @@
 pub mod unpack;
 pub mod info;
 pub mod pack;
-pub mod imgconv;
\ No newline at end of file
+pub mod imgconv;
+pub mod imgdiff;
+pub mod jobs;
+pub mod sheet;
+pub mod tex1;
+pub mod view;
\ No newline at end of file
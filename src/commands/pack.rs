@@ -1,159 +1,573 @@
-
 use clap::ArgMatches;
 use filetime::FileTime;
 
 use std::io::prelude::*;
-use std::io::{BufWriter, SeekFrom};
-use std::fs::File;
-use std::path::PathBuf;
-use byteorder::{LittleEndian, WriteBytesExt};
+use std::io::{self, BufWriter};
+use std::fs::{self, File, Metadata};
+use std::path::{Path, PathBuf};
+use std::collections::{BTreeMap, HashSet};
 
-use super::super::types::{DDSubFileHeader, DDFiletype};
+use super::super::types::{self, DDMainHeader, DDSubFileHeader, DDFiletype};
+use super::super::sidecar::Sidecar;
+use super::super::tex2;
+use super::imgconv;
+use super::tex1;
 use super::super::errors::*;
 
+/// Size of the buffer each file is streamed through on its way into the archive.
+const COPY_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Prints a status line, to stderr if the archive itself is going to stdout.
+macro_rules! status {
+    ($opts:expr, $($arg:tt)*) => {
+        if $opts.to_stdout { eprintln!($($arg)*); } else { println!($($arg)*); }
+    }
+}
+
+/// Where the contents of an entry come from.
+///
+/// Anything that needs converting is only converted as it's written, one entry at a time,
+/// so memory use doesn't grow with the number of entries.
+enum EntrySource {
+    /// Streamed straight out of a file on disk
+    File(PathBuf),
+    /// An image, encoded to tex2 on the way in
+    Texture(PathBuf, Option<Sidecar>),
+    /// An OBJ or glTF file, encoded to tex1 on the way in
+    Model(PathBuf, Option<Sidecar>),
+    /// A vertex and fragment shader, put back together on the way in under the name stored inside
+    Glsl { internal_name: String, vertex: PathBuf, fragment: PathBuf },
+    /// Nothing at all, for folder markers
+    Empty
+}
+
+struct PackEntry {
+    source: EntrySource,
+    header: DDSubFileHeader
+}
+
+struct PackOptions {
+    to_stdout: bool,
+    zerotime: bool,
+    /// Types given on the command line, by path relative to the top-level directory
+    type_overrides: Vec<(String, DDFiletype)>,
+    /// How to resize images to match the textures they came from, if they should be
+    match_original: Option<imgconv::ResizeMode>
+}
+
 pub fn execute(matches: &ArgMatches) -> Result<()> {
+    let opts = PackOptions {
+        // Writing the archive to stdout means our chatter has to go somewhere else
+        to_stdout: matches.value_of("ARCHIVE").unwrap() == "-",
+        zerotime: matches.is_present("zerotime"),
+        type_overrides: match matches.values_of("types") {
+            Some(values) => values.map(parse_type_override).collect::<Result<_>>()?,
+            None => vec![]
+        },
+        match_original: if matches.is_present("matchoriginal") {
+            Some(imgconv::ResizeMode::from_name(matches.value_of("resize").unwrap_or("fit")).unwrap())
+        } else {
+            None
+        }
+    };
+
     let folder = PathBuf::from(matches.value_of("DIR").unwrap());
     if !folder.is_dir() {
-        //TODO this is a horrible error message
-        panic!("This is an error message that shouldn't happen but does because clap");
+        return Err(format!("{} is not a directory", folder.display()).into());
+    }
+    // Catch typos, which would otherwise quietly leave the file with its usual type
+    for (path, _) in opts.type_overrides.iter() {
+        if !folder.join(path).is_file() {
+            return Err(format!("--type {}: there's no file {} in {}", path, path, folder.display()).into());
+        }
     }
 
     // Array of files that will be in the header
-    let mut files: Vec<(PathBuf, DDSubFileHeader)> = vec![];
+    let mut files: Vec<PackEntry> = vec![];
+
+    status!(opts, "## Building file list");
+    collect_dir(&folder, None, &opts, &mut files)?;
+    status!(opts, "## Built list of {} file{}", files.len(), if files.len() == 1 {""} else {"s"});
 
     // Length of all of the subheaders
     // Used later to calculate offsets
     // Starts at 2 due to the 2 null bytes at the end of the header
-    let mut total_subheader_length: u32 = 2;
+    let total_subheader_length: u32 = files.iter().fold(2u32, |acc, entry| {
+        // filetype(u16) + filename with null term + offset(u32) + size(u32) + timestamp(u32)
+        acc + 2 + (entry.header.filename.len() as u32 + 1) + 4 + 4 + 4
+    });
+
+    status!(opts, "Total subheader length: {}B", total_subheader_length);
+    let files_start_at: u32 = total_subheader_length + 12;
+    status!(opts, "First file offset at: {}", files_start_at);
+
+    // Lay the files out one after the other, in the same order they'll be written in.
+    // Everything after this point is written strictly front to back, so no seeking is needed.
+    let mut cur_offset: u32 = files_start_at;
+    for entry in files.iter_mut() {
+        entry.header.offset = cur_offset;
+        cur_offset = cur_offset.checked_add(entry.header.size)
+            .ok_or("Archive would be bigger than 4GiB, which the format can't address")?;
+    }
+
+    status!(opts, "Beginning file output");
+    let output: Box<dyn Write> = if opts.to_stdout {
+        Box::new(io::stdout())
+    } else {
+        Box::new(File::create(matches.value_of("ARCHIVE").unwrap())
+            .chain_err(|| "Failed to open output archive")?)
+    };
+    let mut output_archive = BufWriter::new(output);
+
+    DDMainHeader::new(total_subheader_length).write(&mut output_archive)
+        .chain_err(|| "Failed to write main header to output file")?;
+    status!(opts, "Wrote main header");
 
-    // Biggest file we'll need to load into memory
-    //TODO replace this with a smaller buffer in a loop
-    let mut biggest_file_size: usize = 0;
+    // Export the whole subheader section
+    for entry in files.iter() {
+        entry.header.write(&mut output_archive)
+            .chain_err(|| format!("Failed to write header for file {} to output archive", entry.header.filename))?;
+    }
+    // Double null byte to signify header end
+    output_archive.write_all(&[0, 0]).chain_err(|| "Failed to write header end to output file")?;
+    status!(opts, "Wrote subheader");
 
-    let iter = folder.read_dir().chain_err(|| "Failed to read file list from directory")?;
-    println!("## Building file list");
+    // Let's start reading files!
+    // Every file goes through the same fixed-size buffer, so memory use doesn't depend on file size.
+    let mut buf = vec![0u8; COPY_BUFFER_SIZE];
+    for entry in files {
+        let subheader = entry.header;
+        let converted = match entry.source {
+            EntrySource::File(filepath) => Err(filepath),
+            EntrySource::Texture(filepath, sidecar) => Ok(texture_data(&filepath, sidecar.as_ref(), &opts)?),
+            EntrySource::Model(filepath, sidecar) => Ok(tex1::encode_mesh(&filepath, sidecar.as_ref())?.0),
+            EntrySource::Glsl { internal_name, vertex, fragment } =>
+                Ok(glsl_data(&subheader.filename, &internal_name, &vertex, &fragment)?),
+            EntrySource::Empty => continue
+        };
+        let filepath = match converted {
+            Err(filepath) => filepath,
+            Ok(data) => {
+                if data.len() != subheader.size as usize {
+                    return Err(format!("{} came out as {} bytes instead of the {} that were planned for. {}",
+                                       subheader.filename, data.len(), subheader.size,
+                                       "Did something change it while packing?").into());
+                }
+                output_archive.write_all(&data)
+                    .chain_err(|| format!("Failed to write {} to output archive", subheader.filename))?;
+                status!(opts, "Wrote {}", subheader.filename);
+                continue;
+            }
+        };
+        let reader = File::open(filepath.clone())
+            .chain_err(|| format!("Failed to open file {}", filepath.display()))?;
+        // Read at most one byte more than we expect, which is enough to tell if the file grew
+        let bytes_copied = copy_through(&mut reader.take(u64::from(subheader.size) + 1),
+                                        &mut output_archive, &mut buf)
+            .chain_err(|| format!("Failed to copy file {} into output archive", filepath.display()))?;
+
+        if bytes_copied != u64::from(subheader.size) {
+            return Err(format!("Something changed the file {} very quickly. {}", filepath.display(),
+                               "We detect and deny this to prevent a race condition.").into());
+        }
+        status!(opts, "Wrote {}", subheader.filename);
+    }
+    output_archive.flush().chain_err(|| "Failed to write to output file")?;
+
+    status!(opts, "Built archive {}", matches.value_of("ARCHIVE").unwrap());
+    Ok(())
+}
+
+/// Adds everything in `dir` to `files`, recursing into subdirectories.
+///
+/// Each folder's contents come first, followed by the folder's marker, with the files that
+/// aren't in any folder at the very end. That's the order `unpack` has always read the game's
+/// archives in, where it makes a folder for each marker and puts everything before it inside.
+/// It only ever handled folders one deep, so the naming of nested ones is this tool's own:
+/// `folder_name` is `None` for the top-level directory, which doesn't get a marker, and nested
+/// folders get their path relative to it, joined with `/`.
+///
+/// A `NAME.foldermarker` file next to a folder (as `unpack --foldermarkers` writes) takes the
+/// place of the marker that folder would get.
+fn collect_dir(dir: &Path, folder_name: Option<String>, opts: &PackOptions, files: &mut Vec<PackEntry>) -> Result<()> {
+    let iter = dir.read_dir()
+        .chain_err(|| format!("Failed to read file list from directory {}", dir.display()))?;
+
+    let mut subdirs: Vec<PathBuf> = vec![];
+    let mut dir_files: Vec<PackEntry> = vec![];
+    // GLSL shaders that unpack split up, by name: (vertex, fragment)
+    let mut shaders: BTreeMap<String, (Option<PathBuf>, Option<PathBuf>)> = BTreeMap::new();
+    // Images to be encoded to textures
+    let mut images: Vec<(PathBuf, Metadata, Option<Sidecar>)> = vec![];
     for file in iter {
-        let file = file.unwrap();
-        let metadata = file.metadata().chain_err(|| "Failed to read file metadata")?;
-        let filesize = metadata.len() as u32;
+        let file = file.chain_err(|| format!("Failed to read file list from directory {}", dir.display()))?;
         let filepath = file.path();
-        let filetype;
-
-        // Determine saved filename
-        let mut filename = file.path();
-        filename.set_extension("");
-        let filename = String::from(filename.file_name().unwrap().to_string_lossy());
-
-        // Determine file type
-
-        if let Some(ext) = filepath.extension() {
-            match DDFiletype::from_extension(&ext.to_string_lossy()) {
-                Some(newtype) => {
-                    filetype = newtype;
-                },
-                None => {
-                    println!("{}: Unrecognized file type {:?}", filepath.display(), ext);
-                    println!("TODO: implement .dd_0xXX detection");
-                    panic!(); //TODO this
-                }
+        // Follow symlinks rather than packing the links themselves
+        let metadata = fs::metadata(&filepath)
+            .chain_err(|| format!("Failed to read file metadata for {}", filepath.display()))?;
+        let extension = filepath.extension().map(|ext| ext.to_string_lossy().into_owned());
+        if metadata.is_dir() {
+            subdirs.push(filepath);
+        } else if extension.as_ref().is_some_and(|ext| ext == "vert" || ext == "frag") {
+            let name = filepath.file_stem().unwrap().to_string_lossy().into_owned();
+            let halves = shaders.entry(name).or_insert((None, None));
+            if extension.unwrap() == "vert" {
+                halves.0 = Some(filepath);
+            } else {
+                halves.1 = Some(filepath);
+            }
+        } else if !Sidecar::is_sidecar(&filepath) {
+            let relative = relative_name(&folder_name, &filepath);
+            let sidecar = Sidecar::load_for(&filepath)?;
+            // Images get turned into textures, unless they've been told to be something else
+            let is_image = extension.as_ref().is_some_and(|ext| imgconv::image_format(ext).is_some());
+            // and OBJ and glTF files into models, unless they've been told to be something else
+            let is_mesh = tex1::is_mesh(&filepath);
+            // Being told it's the type it'd be encoded to anyway still means encoding it
+            let filetype = explicit_type(&filepath, &relative, sidecar.as_ref(), opts)?;
+            if (is_mesh && filetype == Some(DDFiletype::Texture2)) || (is_image && filetype == Some(DDFiletype::Texture1)) {
+                return Err(format!("{} is {}, so it can't be encoded as a {}", filepath.display(),
+                                   if is_image {"an image"} else {"a model"}, filetype.unwrap()).into());
+            }
+            if is_image && (filetype.is_none() || filetype == Some(DDFiletype::Texture2)) {
+                images.push((filepath, metadata, sidecar));
+            } else if is_mesh && (filetype.is_none() || filetype == Some(DDFiletype::Texture1)) {
+                dir_files.push(model_entry(filepath, &metadata, sidecar, opts)?);
+            } else {
+                dir_files.push(file_entry(filepath, &relative, &metadata, sidecar.as_ref(), opts)?);
             }
-        } else {
-            println!("{} has no extension, so we can't determine its file type.", filepath.display());
-            println!("If you need to pass a custom type, use .dd_0xXX, where XX is a number between 00 and FF.");
-            panic!(); //TODO this
         }
+    }
 
-        // Determine Timestamp
-        let mtime = if matches.is_present("zerotime") {
-            0u32
-        } else {
-            FileTime::from_last_modification_time(&metadata).seconds_relative_to_1970() as u32
-        };
+    // Hand-drawn mipmap levels and split-out alpha go inside their texture, not next to it
+    let mut level_files: HashSet<PathBuf> = images.iter()
+        .filter(|(_, _, sidecar)| sidecar.as_ref().is_some_and(imgconv::uses_mipmap_files))
+        .flat_map(|(filepath, _, _)| (1..32).map(move |i| imgconv::mipmap_file_name(filepath, i)))
+        .collect();
+    let alpha_files: Vec<PathBuf> = images.iter()
+        .filter(|(_, _, sidecar)| sidecar.as_ref().is_some_and(imgconv::splits_alpha))
+        .flat_map(|(filepath, _, _)| (0..32).map(move |i| match i {
+            0 => imgconv::alpha_file_name(filepath),
+            i => imgconv::alpha_file_name(&imgconv::mipmap_file_name(filepath, i))
+        }))
+        .collect();
+    level_files.extend(alpha_files);
+    for (filepath, metadata, sidecar) in images {
+        if !level_files.contains(&filepath) {
+            dir_files.push(image_entry(filepath, &metadata, sidecar, opts)?);
+        }
+    }
 
-        //TODO remove this because it's dumb
-        if filesize as usize > biggest_file_size {
-            biggest_file_size = filesize as usize;
+    // Put the shaders back together, complaining about all the missing halves at once
+    let mut missing = vec![];
+    for (name, halves) in shaders {
+        match halves {
+            (Some(vertex), Some(fragment)) => dir_files.push(glsl_entry(name, &vertex, &fragment, opts)?),
+            (Some(vertex), None) => missing.push(format!("{} has no matching {}.frag", vertex.display(), name)),
+            (None, Some(fragment)) => missing.push(format!("{} has no matching {}.vert", fragment.display(), name)),
+            (None, None) => unreachable!()
         }
+    }
+    if !missing.is_empty() {
+        return Err(format!("Found GLSL shaders with a missing half:\n{}", missing.join("\n")).into());
+    }
 
-        // Give an update to the user
-        println!("{}: {}, {}B",
-                 filepath.display(),
-                 filetype,
-                 filesize
-        );
+    // Sort everything alphabetically
+    // This makes packing deterministic.
+    // (no relying on the semi-random order the FS gives them to us)
+    subdirs.sort();
+    dir_files.sort_by(|a, b| a.header.filename.cmp(&b.header.filename));
 
-        // Increment the subheader length
-        // filetype(u16) + filename with null term + offset(u32) + size(u32) + timestamp(u32)
-        total_subheader_length += 2u32 + (filename.len() as u32 + 1) + 4 + 4 + 4;
+    for subdir in subdirs {
+        let name = relative_name(&folder_name, &subdir);
+        collect_dir(&subdir, Some(name), opts, files)?;
+    }
+
+    files.append(&mut dir_files);
 
-        // Finally, save the data away
-        files.push((filepath, DDSubFileHeader {
-            filename: filename,
+    let marker_file = dir.file_name()
+        .and_then(|name| dir.parent().map(|parent| parent.join(format!("{}.foldermarker", name.to_string_lossy()))));
+    if marker_file.is_some_and(|file| file.is_file()) {
+        return Ok(());
+    }
+    if let Some(name) = folder_name {
+        let metadata = fs::metadata(dir)
+            .chain_err(|| format!("Failed to read directory metadata for {}", dir.display()))?;
+        status!(opts, "{}: {}", dir.display(), DDFiletype::FolderMarker);
+        files.push(PackEntry {
+            source: EntrySource::Empty,
+            header: DDSubFileHeader {
+                filename: name,
+                file_type: DDFiletype::FolderMarker,
+                timestamp: timestamp(&metadata, opts),
+                size: 0,
+                offset: 0
+            }
+        });
+    }
+    Ok(())
+}
+
+/// `path`'s name, relative to the top-level directory.
+fn relative_name(folder_name: &Option<String>, path: &Path) -> String {
+    let name = path.file_name().unwrap().to_string_lossy().into_owned();
+    match *folder_name {
+        Some(ref parent) => format!("{}/{}", parent, name),
+        None => name
+    }
+}
+
+/// Parses a `PATH=TYPE` type override from the command line.
+fn parse_type_override(arg: &str) -> Result<(String, DDFiletype)> {
+    let pos = arg.rfind('=')
+        .ok_or_else(|| format!("Type override {} should look like PATH=TYPE", arg))?;
+    let filetype = DDFiletype::from_name(&arg[pos+1..])
+        .ok_or_else(|| format!("Type override {}: unrecognized type {}", arg, &arg[pos+1..]))?;
+    Ok((arg[..pos].trim_start_matches("./").to_string(), filetype))
+}
+
+/// Works out the header for a single file on disk.
+///
+/// `relative` is the file's path relative to the top-level directory, used to find type overrides.
+fn file_entry(filepath: PathBuf, relative: &str, metadata: &Metadata, sidecar: Option<&Sidecar>, opts: &PackOptions) -> Result<PackEntry> {
+    if metadata.len() > u64::from(u32::MAX) {
+        return Err(format!("{} is too big to fit in an archive ({} bytes)",
+                           filepath.display(), metadata.len()).into());
+    }
+    let filesize = metadata.len() as u32;
+
+    // Determine saved filename
+    let filename = entry_name(&filepath);
+
+    // Determine file type
+    let filetype = if let Some(t) = explicit_type(&filepath, relative, sidecar, opts)? {
+        t
+    } else if let Some(ext) = filepath.extension() {
+        DDFiletype::from_extension(&ext.to_string_lossy())
+            .ok_or_else(|| format!("{}: Unrecognized file type {:?}. {}", filepath.display(), ext,
+                                   "Rename it to .dd_0xXX, or give it a type with --type or a sidecar."))?
+    } else {
+        return Err(format!("{} has no extension, so we can't determine its file type. {}", filepath.display(),
+                           "Give it a .dd_0xXX extension, or a type with --type or a sidecar.").into());
+    };
+
+    // Folder markers keep where they are, so nested folders don't lose their parents
+    let filename = if filetype == DDFiletype::FolderMarker {
+        let mut relative = PathBuf::from(relative);
+        relative.set_extension("");
+        relative.to_string_lossy().into_owned()
+    } else {
+        filename
+    };
+
+    // Give an update to the user
+    status!(opts, "{}: {}, {}B",
+             filepath.display(),
+             filetype,
+             filesize
+    );
+
+    Ok(PackEntry {
+        header: DDSubFileHeader {
+            filename,
             file_type: filetype,
-            timestamp: mtime,
+            timestamp: timestamp(metadata, opts),
             size: filesize,
             offset: 0
-        }));
+        },
+        source: EntrySource::File(filepath)
+    })
+}
 
+/// The type a file has been given on the command line or in its sidecar, if any.
+/// The command line wins over the sidecar.
+fn explicit_type(filepath: &Path, relative: &str, sidecar: Option<&Sidecar>, opts: &PackOptions) -> Result<Option<DDFiletype>> {
+    if let Some(&(_, t)) = opts.type_overrides.iter().find(|(path, _)| path == relative) {
+        Ok(Some(t))
+    } else if let Some(name) = sidecar.and_then(|s| s.get("type")) {
+        DDFiletype::from_name(name)
+            .ok_or_else(|| format!("{}: sidecar has unrecognized type {}", filepath.display(), name).into())
+            .map(Some)
+    } else {
+        Ok(None)
     }
-    println!("## Built list of {} file{}", files.len(), if files.len() == 1 {""} else {"s"});
+}
 
-    // Sort file list alphabetically
-    // This makes packing deterministic.
-    // (no relying on the semi-random order the FS gives them to us)
-    files.sort_by(|a, b| a.1.filename.cmp(&b.1.filename));
-    println!("Sorted file list.");
+/// The name a file gets inside the archive, which is just its name without the extension.
+fn entry_name(filepath: &Path) -> String {
+    let mut filename = filepath.to_path_buf();
+    filename.set_extension("");
+    String::from(filename.file_name().unwrap().to_string_lossy())
+}
 
-    println!("Total subheader length: {}B", total_subheader_length);
-    let files_start_at: u32 = total_subheader_length + 12;
-    println!("First file offset at: {}", files_start_at);
+/// Plans out encoding an image into a texture.
+///
+/// How many mipmap levels it gets comes from `mipmaps = ...` in its sidecar:
+/// `full` (the default), `none`, or a number of levels counting the full-size image.
+/// With `mipmapfiles = yes`, levels are read from `name_1.png` and so on where they exist,
+/// and `mipmapfilter = box|triangle|lanczos` picks how the rest are shrunk.
+/// With `splitalpha = yes`, alpha comes from `name_alpha.png` (and `name_1_alpha.png` and so on).
+/// Anything else unpack noted down about the original texture is put back as well.
+/// With --match-original, images that aren't the size of the texture they came from are resized to it,
+/// and get as many mipmap levels as it had.
+///
+/// The texture's size comes from the image's dimensions, so the image itself isn't read until
+/// `texture_data` encodes it.
+fn image_entry(filepath: PathBuf, metadata: &Metadata, sidecar: Option<Sidecar>, opts: &PackOptions) -> Result<PackEntry> {
+    let (width, height, count) = imgconv::planned_texture(&filepath, sidecar.as_ref(), &encode_options(&filepath, sidecar.as_ref(), opts)?)?;
+    let trailing = match sidecar {
+        Some(ref sidecar) => imgconv::original_trailing(sidecar)
+            .chain_err(|| format!("Failed to read {}'s sidecar", filepath.display()))?.len(),
+        None => 0
+    };
+    let size = tex2::encoded_length(width, height, count as u32)
+        .and_then(|length| length.checked_add(trailing))
+        .filter(|&length| length <= u32::MAX as usize)
+        .ok_or_else(|| format!("{} is too big to fit in an archive once it's a {}x{} texture",
+                               filepath.display(), width, height))?;
 
+    status!(opts, "{}: {} ({}x{}, {} mipmap level{}), {}B",
+             filepath.display(),
+             DDFiletype::Texture2,
+             width, height,
+             count, if count == 1 {""} else {"s"},
+             size
+    );
 
-    println!("Beginning file output");
-    let mut output_archive = BufWriter::new(File::create(matches.value_of("ARCHIVE").unwrap())
-        .chain_err(|| "Failed to open output archive")?);
+    Ok(PackEntry {
+        header: DDSubFileHeader {
+            filename: entry_name(&filepath),
+            file_type: DDFiletype::Texture2,
+            timestamp: timestamp(metadata, opts),
+            size: size as u32,
+            offset: 0
+        },
+        source: EntrySource::Texture(filepath, sidecar)
+    })
+}
 
-    //TODO switch this to a struct
-    output_archive.write(b":hx:rg:\x01")
-        .chain_err(|| "Failed to write magic bytes to output file")?;
-    output_archive.write_u32::<LittleEndian>(total_subheader_length)
-        .chain_err(|| "Failed to write header start to output file")?;
-    println!("Wrote main header");
+/// Encodes an image into a texture, as planned out by `image_entry`.
+fn texture_data(filepath: &Path, sidecar: Option<&Sidecar>, opts: &PackOptions) -> Result<Vec<u8>> {
+    let (tex2image, messages) = imgconv::encode_image(filepath, sidecar, &encode_options(filepath, sidecar, opts)?)?;
+    for message in messages {
+        status!(opts, "{}", message);
+    }
+    let mut data = vec![];
+    tex2image.save(&mut data)
+        .chain_err(|| format!("Failed to convert {} to a texture", filepath.display()))?;
+    Ok(data)
+}
 
-    // Export the whole subheader section
-    let mut cur_offset: u32 = files_start_at;
-    for &mut (_, ref mut subheader) in files.iter_mut() {
-        subheader.offset = cur_offset;
-        subheader.write(&mut output_archive)
-            .chain_err(|| format!("Failed to write header for file {} to output archive", subheader.filename))?;
-        cur_offset += subheader.size;
+/// How pack encodes images: going by their sidecars, with a full set of mipmaps unless they say otherwise.
+fn encode_options(filepath: &Path, sidecar: Option<&Sidecar>, opts: &PackOptions) -> Result<imgconv::EncodeOptions> {
+    Ok(imgconv::EncodeOptions {
+        format: None,
+        alpha_file: None,
+        original: None,
+        match_original: opts.match_original,
+        full_mipmaps: false,
+        default_mipmaps: "full",
+        filter: None,
+        mipmap_files: false,
+        pixels: imgconv::PixelOptions::from_sidecar(sidecar)
+            .chain_err(|| format!("Failed to read {}'s sidecar", filepath.display()))?
+    })
+}
+
+/// Plans out encoding an OBJ or glTF file into a model, with whatever its sidecar from
+/// `tex1 export` says the original had that they don't.
+///
+/// There's no knowing how big a model is without building it, so it's built here just to find
+/// out and then thrown away, and built again when it's written.
+fn model_entry(filepath: PathBuf, metadata: &Metadata, sidecar: Option<Sidecar>, opts: &PackOptions) -> Result<PackEntry> {
+    let (data, log) = tex1::encode_mesh(&filepath, sidecar.as_ref())?;
+    for line in log {
+        status!(opts, "{}", line);
     }
-    // Double null byte to signify header end
-    output_archive.write(&[0, 0]).chain_err(|| "Failed to write header end to output file")?;
-    println!("Wrote subheader");
+    status!(opts, "{}: {}, {}B", filepath.display(), DDFiletype::Texture1, data.len());
+    Ok(PackEntry {
+        header: DDSubFileHeader {
+            filename: entry_name(&filepath),
+            file_type: DDFiletype::Texture1,
+            timestamp: timestamp(metadata, opts),
+            size: data.len() as u32,
+            offset: 0
+        },
+        source: EntrySource::Model(filepath, sidecar)
+    })
+}
 
-    // Let's start reading files!
-    // Preallocate the size of the biggest file because avoid reallocs
-    let mut buf = Vec::with_capacity(biggest_file_size);
-    for (filepath, subheader) in files {
-        let mut reader = File::open(filepath.clone())
-            .chain_err(|| format!("Failed to open file {}", filepath.display()))?;
-        let bytes_read = reader.read_to_end(&mut buf)
-            .chain_err(|| format!("Failed to read file {}", filepath.display()))?;
+/// Plans out combining a vertex and fragment shader back into the single GLSL entry they came from.
+///
+/// The name stored inside the shader is taken from `name = ...` in the vertex shader's sidecar,
+/// which unpack writes when it differs from the entry's name.
+fn glsl_entry(name: String, vertex: &Path, fragment: &Path, opts: &PackOptions) -> Result<PackEntry> {
+    let read_metadata = |path: &Path| fs::metadata(path)
+        .chain_err(|| format!("Failed to read file metadata for {}", path.display()));
+    let (vertex_metadata, fragment_metadata) = (read_metadata(vertex)?, read_metadata(fragment)?);
 
-        if bytes_read as u32 != subheader.size {
-            return Err(format!("Something changed the file {} very quickly. {}", filepath.display(),
-                               "We detect and deny this to prevent a race condition.").into());
-        }
+    let internal_name = Sidecar::load_for(vertex)?
+        .and_then(|sidecar| sidecar.get("name").map(String::from))
+        .unwrap_or_else(|| name.clone());
 
-        output_archive.seek(SeekFrom::Start(subheader.offset as u64))
-            .chain_err(|| "Failed to seek within output file")?;
-        output_archive.write_all(&mut buf.as_ref())
-            .chain_err(|| "Failed to write to output file")?;
-        buf.clear();
-        println!("Wrote {}", subheader.filename);
+    // Three u32 lengths, then the name and both shaders
+    let size = 12 + internal_name.len() as u64 + vertex_metadata.len() + fragment_metadata.len();
+    if size > u64::from(u32::MAX) {
+        return Err(format!("GLSL shader {} is too big to fit in an archive", name).into());
     }
 
-    println!("Built archive {}", matches.value_of("ARCHIVE").unwrap());
-    Ok(())
-}
\ No newline at end of file
+    status!(opts, "{} + {}: {}, {}B",
+             vertex.display(),
+             fragment.display(),
+             DDFiletype::GLSL,
+             size
+    );
+
+    Ok(PackEntry {
+        header: DDSubFileHeader {
+            filename: name,
+            file_type: DDFiletype::GLSL,
+            timestamp: timestamp(&vertex_metadata, opts).max(timestamp(&fragment_metadata, opts)),
+            size: size as u32,
+            offset: 0
+        },
+        source: EntrySource::Glsl { internal_name, vertex: vertex.to_path_buf(), fragment: fragment.to_path_buf() }
+    })
+}
+
+/// Puts a GLSL shader back together, as planned out by `glsl_entry`.
+fn glsl_data(name: &str, internal_name: &str, vertex: &Path, fragment: &Path) -> Result<Vec<u8>> {
+    let read_source = |path: &Path| -> Result<String> {
+        let mut source = String::new();
+        File::open(path).and_then(|mut f| f.read_to_string(&mut source))
+            .chain_err(|| format!("Failed to read GLSL shader {}", path.display()))?;
+        Ok(source)
+    };
+    let mut data = vec![];
+    types::write_glsl_file(&mut data, internal_name, &read_source(vertex)?, &read_source(fragment)?)
+        .chain_err(|| format!("Failed to combine GLSL shader {}", name))?;
+    Ok(data)
+}
+
+fn timestamp(metadata: &Metadata, opts: &PackOptions) -> u32 {
+    if opts.zerotime {
+        0u32
+    } else {
+        FileTime::from_last_modification_time(metadata).seconds_relative_to_1970() as u32
+    }
+}
+
+/// Copies everything from `src` to `dst` through `buf`, returning the number of bytes copied.
+fn copy_through<R: Read, W: Write>(src: &mut R, dst: &mut W, buf: &mut [u8]) -> io::Result<u64> {
+    let mut total = 0u64;
+    loop {
+        let len = match src.read(buf) {
+            Ok(0) => return Ok(total),
+            Ok(len) => len,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e)
+        };
+        dst.write_all(&buf[..len])?;
+        total += len as u64;
+    }
+}
@@ -0,0 +1,169 @@
+use clap::ArgMatches;
+use image::{imageops, GenericImageView, ImageBuffer, Pixel, Rgba, RgbaImage};
+
+use std::path::{Path, PathBuf};
+
+use super::super::archive::MappedArchive;
+use super::super::font::{self, GLYPH_HEIGHT};
+use super::super::tex2::DDTex2Image;
+use super::super::types::DDFiletype;
+use super::super::errors::*;
+use super::imgconv;
+
+/// Space around each cell and between a texture and its label.
+const PADDING: u32 = 6;
+/// Space between the lines of a label.
+const LINE_SPACING: u32 = 2;
+const BACKGROUND: Rgba<u8> = Rgba([32, 32, 32, 255]);
+const TEXT_COLOUR: Rgba<u8> = Rgba([224, 224, 224, 255]);
+/// The two colours of the checkerboard that shows through transparent textures.
+const CHECKER: [Rgba<u8>; 2] = [Rgba([56, 56, 56, 255]), Rgba([80, 80, 80, 255])];
+const CHECKER_SIZE: u32 = 8;
+/// Biggest `--cellsize` allowed, which is already bigger than any texture in the game.
+const MAX_CELL_SIZE: u32 = 4096;
+
+/// A texture that's going on the sheet.
+struct SheetEntry {
+    /// Folder and name, like `sub/good`
+    name: String,
+    /// Where it was in the archive, for sorting by archive order
+    index: usize,
+    texture: DDTex2Image
+}
+
+pub fn execute(matches: &ArgMatches) -> Result<()> {
+    let archive_path = PathBuf::from(matches.value_of("FILE").unwrap());
+    let output_file = match matches.value_of("output") {
+        Some(file) => PathBuf::from(file),
+        None => {
+            let mut name = archive_path.file_name().unwrap_or_default().to_os_string();
+            name.push("_sheet.png");
+            archive_path.with_file_name(name)
+        }
+    };
+    let format = imgconv::output_format(&output_file)?;
+    let cell_size = match matches.value_of("cellsize") {
+        Some(size) => size.parse::<u32>().ok().filter(|&s| (8..=MAX_CELL_SIZE).contains(&s))
+            .ok_or_else(|| format!("Invalid cell size {}, it has to be a number of pixels from 8 to {}", size, MAX_CELL_SIZE))?,
+        None => 128
+    };
+
+    let mut entries = read_textures(&archive_path)?;
+    if entries.is_empty() {
+        return Err(format!("{} doesn't have any textures in it", archive_path.display()).into());
+    }
+    match matches.value_of("sort").unwrap_or("name") {
+        "name" => entries.sort_by(|a, b| a.name.cmp(&b.name)),
+        // Biggest first, then by name so equal sizes come out the same every time
+        "size" => entries.sort_by(|a, b| {
+            let area = |e: &SheetEntry| e.texture.width as u64 * e.texture.height as u64;
+            area(b).cmp(&area(a)).then_with(|| a.name.cmp(&b.name))
+        }),
+        _ => entries.sort_by_key(|e| e.index)
+    }
+
+    // More columns than textures would only add empty space
+    let columns = match matches.value_of("columns") {
+        Some(columns) => columns.parse::<u32>().ok().filter(|&c| c > 0)
+            .ok_or_else(|| format!("Invalid column count {}", columns))?,
+        None => (entries.len() as f64).sqrt().ceil() as u32
+    }.min(entries.len() as u32);
+    let sheet = build_sheet(&entries, cell_size, columns)?;
+    imgconv::save_image(&output_file, &sheet, format)
+        .chain_err(|| format!("Failed to save sheet to {}", output_file.display()))?;
+    println!("Saved {} texture{} to {} ({}x{})",
+             entries.len(),
+             if entries.len() == 1 {""} else {"s"},
+             output_file.display(),
+             sheet.width(),
+             sheet.height()
+    );
+    Ok(())
+}
+
+/// Decodes every texture in the archive, warning about (and leaving out) any that don't decode.
+fn read_textures(archive_path: &Path) -> Result<Vec<SheetEntry>> {
+    let archive = MappedArchive::open(archive_path)?;
+    let mut entries = vec![];
+    for (index, (entry, folder)) in archive.entries().into_iter().zip(archive.entry_folders()).enumerate() {
+        if entry.file_type != DDFiletype::Texture2 {
+            continue;
+        }
+        let name = match folder {
+            Some(folder) => format!("{}/{}", folder, entry.filename_lossy()),
+            None => entry.filename_lossy().into_owned()
+        };
+        match DDTex2Image::read(archive.data(&entry)) {
+            Ok(texture) => entries.push(SheetEntry { name, index, texture }),
+            Err(e) => println!("Warning: leaving out {}, which can't be decoded: {}", name, e)
+        }
+    }
+    Ok(entries)
+}
+
+/// Lays the textures out on a grid, `columns` wide, each scaled to fit in `cell_size` pixels
+/// and labelled with its name, size and mipmap count underneath.
+fn build_sheet(entries: &[SheetEntry], cell_size: u32, columns: u32) -> Result<RgbaImage> {
+    let rows = (entries.len() as u32).div_ceil(columns);
+    let label_height = 2 * GLYPH_HEIGHT + LINE_SPACING;
+    let (cell_width, cell_height) = (cell_size + PADDING, cell_size + PADDING + label_height + PADDING);
+    let length = |count: u32, cell: u32| count.checked_mul(cell)?.checked_add(PADDING);
+    let (width, height) = match (length(columns, cell_width), length(rows, cell_height)) {
+        (Some(width), Some(height)) => (width, height),
+        _ => return Err(format!("A sheet of {} textures in {} columns of {} pixels would be too big to make",
+                                entries.len(), columns, cell_size).into())
+    };
+    let mut sheet = ImageBuffer::from_pixel(width, height, BACKGROUND);
+
+    for (i, entry) in entries.iter().enumerate() {
+        let (left, top) = (PADDING + (i as u32 % columns) * cell_width, PADDING + (i as u32 / columns) * cell_height);
+        let thumbnail = thumbnail(&entry.texture, cell_size)?;
+        // Centre it along the bottom of the cell, just above the label
+        let (x, y) = (left + (cell_size - thumbnail.width()) / 2, top + cell_size - thumbnail.height());
+        for ty in 0..thumbnail.height() {
+            for tx in 0..thumbnail.width() {
+                let mut pixel = CHECKER[((tx / CHECKER_SIZE + ty / CHECKER_SIZE) % 2) as usize];
+                pixel.blend(thumbnail.get_pixel(tx, ty));
+                sheet.put_pixel(x + tx, y + ty, pixel);
+            }
+        }
+
+        let levels = entry.texture.mipmap_levels.max(1);
+        let details = format!("{}x{}, {} mip{}", entry.texture.width, entry.texture.height,
+                              levels, if levels == 1 {""} else {"s"});
+        let label_top = top + cell_size + PADDING;
+        font::draw_text(&mut sheet, left, label_top, &font::fit_text(&entry.name, cell_size), TEXT_COLOUR);
+        font::draw_text(&mut sheet, left, label_top + GLYPH_HEIGHT + LINE_SPACING,
+                        &font::fit_text(&details, cell_size), TEXT_COLOUR);
+    }
+    Ok(sheet)
+}
+
+/// Shrinks (or blows up) a texture so it fits in a `size`x`size` square.
+///
+/// The biggest mipmap level that fits is used if there is one, so big textures don't need resizing.
+/// Small ones are scaled up by a whole number, so their pixels stay sharp.
+fn thumbnail(texture: &DDTex2Image, size: u32) -> Result<RgbaImage> {
+    for n in 0..texture.mipmap_levels.max(1) {
+        let level = texture.level(n)?;
+        let (width, height) = level.dimensions();
+        if width <= size && height <= size {
+            let scale = (size / width.max(height)).max(1);
+            let image = level.to_image();
+            return Ok(if scale == 1 {
+                image
+            } else {
+                imageops::resize(&image, width * scale, height * scale, imageops::FilterType::Nearest)
+            });
+        }
+    }
+    // Too big even at the smallest level, so it needs an actual resize
+    let image = texture.level(texture.mipmap_levels.max(1) - 1)?.to_image();
+    let (width, height) = image.dimensions();
+    let (new_width, new_height) = if width >= height {
+        (size, ((height as u64 * size as u64) / width as u64).max(1) as u32)
+    } else {
+        (((width as u64 * size as u64) / height as u64).max(1) as u32, size)
+    };
+    Ok(imageops::resize(&image, new_width, new_height, imageops::FilterType::Triangle))
+}
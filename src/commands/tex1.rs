@@ -0,0 +1,821 @@
+use clap::ArgMatches;
+use nom::IResult;
+
+use std::collections::HashMap;
+use std::fmt::Write as FmtWrite;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use super::super::archive::MappedArchive;
+use super::super::encoding;
+use super::super::json::Json;
+use super::super::sidecar::Sidecar;
+use super::super::tex1::{self, ComponentFormat, PositionLayout, Tex1Header, Tex1Model, Vertex, TEX1_HEADER_LENGTH};
+use super::super::types::DDFiletype;
+use super::super::wireframe;
+use super::super::errors::*;
+use super::imgconv;
+
+/// Formats a model can be exported as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MeshFormat {
+    Obj,
+    Gltf
+}
+
+impl MeshFormat {
+    fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "obj" => Some(MeshFormat::Obj),
+            "gltf" => Some(MeshFormat::Gltf),
+            _ => None
+        }
+    }
+}
+
+pub fn execute(matches: &ArgMatches) -> Result<()> {
+    match matches.subcommand() {
+        ("export", Some(matches)) => export(matches),
+        ("import", Some(matches)) => import(matches),
+        ("render", Some(matches)) => render(matches),
+        _ => Ok(())
+    }
+}
+
+/// Reads FILE, or the entry called ENTRY in it if there is one.
+/// Returns the name to go by along with the data.
+fn read_input(matches: &ArgMatches) -> Result<(String, Vec<u8>)> {
+    let file = PathBuf::from(matches.value_of("FILE").unwrap());
+    match matches.value_of("ENTRY") {
+        Some(entry_name) => {
+            let archive = MappedArchive::open(&file)?;
+            let entry = archive.find(entry_name, DDFiletype::Texture1)
+                .ok_or_else(|| format!("{} has no model called {}", file.display(), entry_name))?;
+            Ok((entry_name.to_string(), archive.data(&entry).to_vec()))
+        },
+        None => {
+            let mut data = vec![];
+            File::open(&file).and_then(|mut f| f.read_to_end(&mut data))
+                .chain_err(|| format!("Failed to read {}", file.display()))?;
+            Ok((file.display().to_string(), data))
+        }
+    }
+}
+
+/// Where output goes when it isn't given: next to FILE, or in the current directory
+/// and named after ENTRY.
+fn default_output(matches: &ArgMatches, extension: &str) -> PathBuf {
+    match matches.value_of("ENTRY") {
+        Some(entry_name) => {
+            let name = Path::new(entry_name).file_name().unwrap_or_default();
+            PathBuf::from(name).with_extension(extension)
+        },
+        None => PathBuf::from(matches.value_of("FILE").unwrap()).with_extension(extension)
+    }
+}
+
+/// Exports a model as OBJ or glTF, and a wireframe of it too if asked.
+fn export(matches: &ArgMatches) -> Result<()> {
+    let (name, data) = read_input(matches)?;
+    let model = Tex1Model::read(&data).chain_err(|| format!("Failed to read {} as a model", name))?;
+
+    let output_file = match matches.value_of("output") {
+        Some(file) => PathBuf::from(file),
+        None => default_output(matches, matches.value_of("format").unwrap_or("obj"))
+    };
+    let format = match matches.value_of("format") {
+        Some(format) => MeshFormat::from_name(format).unwrap(),
+        None => {
+            let ext = output_file.extension().map(|e| e.to_string_lossy()).unwrap_or_default();
+            MeshFormat::from_name(&ext).ok_or_else(|| format!(
+                "Don't know what format to save {} as, use --format to pick one", output_file.display()))?
+        }
+    };
+    let text = match format {
+        MeshFormat::Obj => to_obj(&model, &name),
+        MeshFormat::Gltf => to_gltf(&model, &name)
+    };
+    let mut fout = BufWriter::new(File::create(&output_file)
+        .chain_err(|| format!("Failed to open output file {}", output_file.display()))?);
+    fout.write_all(text.as_bytes())
+        .and_then(|_| fout.flush())
+        .chain_err(|| format!("Failed to save model to {}", output_file.display()))?;
+    println!("Exported {} ({} vertices, {} triangles) to {}",
+             name, model.vertices.len(), model.indices.len() / 3, output_file.display());
+    model_sidecar(&model).save_for(&output_file)?;
+    println!("Saved what the model doesn't cover to {}", Sidecar::path_for(&output_file).display());
+
+    if let Some(wireframe_file) = matches.value_of("wireframe") {
+        let wireframe_file = Path::new(wireframe_file);
+        let format = imgconv::output_format(wireframe_file)?;
+        let points: Vec<[f32; 3]> = model.vertices.iter().map(|v| v.position).collect();
+        let triangles: Vec<[u32; 3]> = model.triangles().collect();
+        let image = wireframe::render(&points, &triangles, &wireframe::VIEWS, 256);
+        imgconv::save_image(wireframe_file, &image, format)
+            .chain_err(|| format!("Failed to save wireframe to {}", wireframe_file.display()))?;
+        println!("Saved wireframe to {}", wireframe_file.display());
+    }
+    Ok(())
+}
+
+/// Draws where a layout says the positions are, so a guess can be checked by eye.
+/// With --guess, tries every layout that fits and draws the most model-like few. UVs and normals
+/// can look like models too, so it's the pictures that settle it, not the scores.
+fn render(matches: &ArgMatches) -> Result<()> {
+    let (name, data) = read_input(matches)?;
+    let header = match tex1::tex1_header(&data) {
+        IResult::Done(_, header) => Some(header),
+        _ => None
+    };
+    let number = |arg: &str| -> Result<Option<usize>> {
+        match matches.value_of(arg) {
+            Some(value) => value.parse().map(Some).chain_err(|| format!("--{} has to be a number", arg)),
+            None => Ok(None)
+        }
+    };
+    let start = number("start")?.unwrap_or(TEX1_HEADER_LENGTH);
+    let size = match matches.value_of("size") {
+        Some(size) => match size.parse::<u32>() {
+            Ok(size) if size >= 1 => size,
+            _ => return Err(format!("--size has to be a number of pixels, at least 1, not {}", size).into())
+        },
+        None => 256
+    };
+    let count = number("count")?;
+    let format = ComponentFormat::from_name(matches.value_of("vertexformat").unwrap_or("f32")).unwrap();
+    let points_only = matches.is_present("points");
+    let output_file = match matches.value_of("output") {
+        Some(file) => PathBuf::from(file),
+        None => default_output(matches, "png")
+    };
+    let image_format = imgconv::output_format(&output_file)?;
+    if start >= data.len() {
+        return Err(format!("{} is only {} bytes long, so nothing starts at {}", name, data.len(), start).into());
+    }
+
+    let layouts = if matches.is_present("guess") {
+        let top = number("top")?.unwrap_or(4);
+        let mut scored: Vec<(PositionLayout, f64)> = candidate_layouts(start, format, number("stride")?)
+            .into_iter()
+            .map(|layout| {
+                let (points, triangles) = hypothesis(&data, header, &layout, count, points_only);
+                (layout, tex1::score_positions(&points, &triangles))
+            })
+            .filter(|&(_, score)| score > 0.0)
+            .collect();
+        if scored.is_empty() {
+            return Err(format!("Nothing in {} looks like positions", name).into());
+        }
+        scored.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap()
+            .then(a.0.stride.cmp(&b.0.stride))
+            .then(a.0.offset.cmp(&b.0.offset)));
+        println!("Tried {} layouts, most model-like first:", scored.len());
+        for &(layout, score) in scored.iter().take(top.max(10)) {
+            println!("    stride {:3}, offset {:3}: {:.3}", layout.stride, layout.offset, score);
+        }
+        scored.into_iter().take(top).map(|(layout, _)| layout).collect()
+    } else {
+        let stride = match number("stride")? {
+            Some(stride) => stride,
+            None => guess_stride(&data, header, start)
+        };
+        vec![PositionLayout { start, stride, offset: number("offset")?.unwrap_or(0), format }]
+    };
+    for (i, layout) in layouts.iter().enumerate() {
+        let (points, triangles) = hypothesis(&data, header, layout, count, points_only);
+        if points.is_empty() {
+            return Err(format!("A {} byte {} position at {} doesn't fit in a {} byte vertex",
+                               layout.format.size() * 3, layout.format.name(), layout.offset, layout.stride).into());
+        }
+        let image = wireframe::render(&points, &triangles, &wireframe::VIEWS, size);
+        let file = if layouts.len() == 1 {
+            output_file.clone()
+        } else {
+            let stem = output_file.file_stem().unwrap_or_default().to_string_lossy();
+            let ext = output_file.extension().unwrap_or_default().to_string_lossy();
+            output_file.with_file_name(format!("{}_{}_s{}_o{}.{}", stem, i + 1, layout.stride, layout.offset, ext))
+        };
+        imgconv::save_image(&file, &image, image_format)
+            .chain_err(|| format!("Failed to save render to {}", file.display()))?;
+        println!("Saved {} {} positions (stride {}, offset {}, from byte {}) and {} triangles to {}",
+                 points.len(), layout.format.name(), layout.stride, layout.offset, layout.start,
+                 triangles.len(), file.display());
+    }
+    Ok(())
+}
+
+/// Whether the header's counts work out with `layout`, going by the file's length.
+/// Returns where the indices would start.
+fn header_fits(data: &[u8], header: Option<Tex1Header>, layout: &PositionLayout) -> Option<usize> {
+    let header = header?;
+    let index_start = layout.start.checked_add((header.vertex_count as usize).checked_mul(layout.stride)?)?;
+    let index_end = index_start.checked_add((header.index_count as usize).checked_mul(4)?)?;
+    if header.vertex_count > 0 && index_end <= data.len() { Some(index_start) } else { None }
+}
+
+/// The positions `layout` reads, and the triangles after them if the header's counts fit
+/// and every index is in range.
+/// Without either of those, it's as many positions as there's room for.
+fn hypothesis(data: &[u8], header: Option<Tex1Header>, layout: &PositionLayout, count: Option<usize>,
+              points_only: bool) -> (Vec<[f32; 3]>, Vec<[u32; 3]>) {
+    let index_start = header_fits(data, header, layout);
+    let count = count
+        .or_else(|| index_start.and(header).map(|h| h.vertex_count as usize))
+        .unwrap_or(usize::MAX);
+    let points = layout.read_positions(data, count);
+    let triangles = match (index_start, header) {
+        (Some(index_start), Some(header)) if !points_only && points.len() == header.vertex_count as usize => {
+            data[index_start..index_start + header.index_count as usize * 4]
+                .chunks_exact(12)
+                .map(|t| {
+                    let index = |i: usize| u32::from_le_bytes([t[i], t[i + 1], t[i + 2], t[i + 3]]);
+                    [index(0), index(4), index(8)]
+                })
+                .collect()
+        },
+        _ => vec![]
+    };
+    if triangles.iter().flatten().any(|&i| i as usize >= points.len()) {
+        return (points, vec![]);
+    }
+    (points, triangles)
+}
+
+/// Goes by the header if its counts fit a `TEX1_VERTEX_SIZE` stride, and by the data if not.
+fn guess_stride(data: &[u8], header: Option<Tex1Header>, start: usize) -> usize {
+    let layout = PositionLayout { start, stride: tex1::TEX1_VERTEX_SIZE, offset: 0, format: ComponentFormat::F32 };
+    if header_fits(data, header, &layout).is_some() {
+        return layout.stride;
+    }
+    tex1::best_stride(&tex1::guess_strides(&data[start..], 64)).unwrap_or(layout.stride)
+}
+
+/// Every stride up to 64 bytes (or just `stride`) with every place a position could go in it.
+fn candidate_layouts(start: usize, format: ComponentFormat, stride: Option<usize>) -> Vec<PositionLayout> {
+    let step = format.size();
+    let strides: Vec<usize> = match stride {
+        Some(stride) => vec![stride],
+        None => (step * 3..=64).step_by(step).collect()
+    };
+    strides.into_iter()
+        .flat_map(|stride| (0..=stride.saturating_sub(step * 3)).step_by(step)
+            .map(move |offset| PositionLayout { start, stride, offset, format }))
+        .collect()
+}
+
+/// Turns an OBJ file back into a model, going by the sidecar that export left next to it if there is one.
+fn import(matches: &ArgMatches) -> Result<()> {
+    let input_file = PathBuf::from(matches.value_of("FILE").unwrap());
+    let output_file = match matches.value_of("output") {
+        Some(file) => PathBuf::from(file),
+        None => input_file.with_extension(DDFiletype::Texture1.extension())
+    };
+    let (data, log) = encode_mesh(&input_file, Sidecar::load_for(&input_file)?.as_ref())?;
+    for line in log {
+        println!("{}", line);
+    }
+    let mut fout = BufWriter::new(File::create(&output_file)
+        .chain_err(|| format!("Failed to open output file {}", output_file.display()))?);
+    fout.write_all(&data)
+        .and_then(|_| fout.flush())
+        .chain_err(|| format!("Failed to save model to {}", output_file.display()))?;
+    println!("Converted model saved to {}", output_file.display());
+    Ok(())
+}
+
+/// Whether `path` is a mesh that `encode_mesh` can turn into a model.
+pub fn is_mesh(path: &Path) -> bool {
+    path.extension().and_then(|ext| MeshFormat::from_name(&ext.to_string_lossy())).is_some()
+}
+
+/// Reads an OBJ or glTF file and encodes it as a tex1 model, putting back anything `sidecar` says
+/// the original had that they can't hold. Returns the model along with anything worth mentioning.
+pub fn encode_mesh(input_file: &Path, sidecar: Option<&Sidecar>) -> Result<(Vec<u8>, Vec<String>)> {
+    let format = input_file.extension()
+        .and_then(|ext| MeshFormat::from_name(&ext.to_string_lossy()))
+        .ok_or_else(|| format!("Don't know how to import {}, it has to be .obj or .gltf", input_file.display()))?;
+    let mut text = String::new();
+    File::open(input_file).and_then(|mut f| f.read_to_string(&mut text))
+        .chain_err(|| format!("Failed to read {}", input_file.display()))?;
+    let (mut model, mut log) = match format {
+        MeshFormat::Obj => read_obj(&text).chain_err(|| format!("Failed to read {} as OBJ", input_file.display()))?,
+        MeshFormat::Gltf => read_gltf(&text, input_file.parent().unwrap_or_else(|| Path::new("")))
+            .chain_err(|| format!("Failed to read {} as glTF", input_file.display()))?
+    };
+    match sidecar {
+        Some(sidecar) => {
+            if let Some(comparison) = restore_model(&mut model, sidecar)
+                .chain_err(|| format!("Failed to read {}'s sidecar", input_file.display()))? {
+                log.push(format!("{}: {}", input_file.display(), comparison));
+            }
+        },
+        None => log.push(format!("{}: no sidecar, so the header's unknown u16 is 0", input_file.display()))
+    }
+    let mut data = vec![];
+    model.save(&mut data).chain_err(|| "Failed to encode model")?;
+    if data.len() > u32::MAX as usize {
+        return Err(format!("{} is too big to fit in an archive once it's a model ({} bytes)",
+                           input_file.display(), data.len()).into());
+    }
+    // Make sure it reads back the way export would read it
+    Tex1Model::read(&data).chain_err(|| format!("{} doesn't make a model that can be read back", input_file.display()))?;
+    log.push(format!("{}: {} vertices, {} triangles", input_file.display(), model.vertices.len(), model.indices.len() / 3));
+    Ok((data, log))
+}
+
+/// What a model has that OBJ and glTF don't: the unknown u16 from its header and anything
+/// after its indices, plus a checksum to tell whether it came back the same.
+fn model_sidecar(model: &Tex1Model) -> Sidecar {
+    let mut sidecar = Sidecar::new();
+    sidecar.set("unknown", model.unknown.to_string());
+    if !model.trailing.is_empty() {
+        sidecar.set("trailing", encoding::to_hex(&model.trailing));
+    }
+    sidecar.set("checksum", format!("{:016x}", model.checksum()));
+    sidecar
+}
+
+/// Puts back what `model_sidecar` noted down, and says how the result compares to the original.
+fn restore_model(model: &mut Tex1Model, sidecar: &Sidecar) -> Result<Option<String>> {
+    if let Some(unknown) = sidecar.get("unknown") {
+        model.unknown = unknown.parse()
+            .chain_err(|| format!("Sidecar has unknown = {}, which isn't a u16", unknown))?;
+    }
+    if let Some(trailing) = sidecar.get("trailing") {
+        model.trailing = encoding::from_hex(trailing)
+            .ok_or_else(|| format!("Sidecar has trailing = {}, which isn't hex", trailing))?;
+    }
+    Ok(match sidecar.get("checksum") {
+        Some(checksum) if u64::from_str_radix(checksum, 16).ok() == Some(model.checksum()) =>
+            Some("identical to the original model".to_string()),
+        Some(_) => Some("differs from the original model".to_string()),
+        None => None
+    })
+}
+
+/// One corner of an OBJ face, as (position, texture coordinates, normal), all counting from 0.
+type Corner = (usize, Option<usize>, Option<usize>);
+
+/// Reads a mesh out of an OBJ file.
+///
+/// Every different combination of position, texture coordinates and normal that a face uses
+/// becomes a vertex, and faces with more than three corners are split into triangles.
+/// If every face uses the same index for all three, as export writes them, the vertices are
+/// kept in the order they're listed so nothing moves around.
+pub fn read_obj(text: &str) -> Result<(Tex1Model, Vec<String>)> {
+    let mut positions: Vec<[f32; 3]> = vec![];
+    let mut uvs: Vec<[f32; 2]> = vec![];
+    let mut normals: Vec<[f32; 3]> = vec![];
+    let mut faces: Vec<Vec<Corner>> = vec![];
+    let mut log = vec![];
+
+    for (n, line) in text.lines().enumerate() {
+        let line_error = |what: &str| -> Error { format!("Line {}: {}", n + 1, what).into() };
+        let mut parts = line.split_whitespace();
+        let keyword = match parts.next() {
+            Some(keyword) => keyword,
+            None => continue
+        };
+        let mut floats = |count: usize| -> Result<Vec<f32>> {
+            let values: Vec<f32> = parts.by_ref().take(count).map(|p| p.parse::<f32>())
+                .collect::<::std::result::Result<_, _>>()
+                .map_err(|_| line_error("not a number"))?;
+            if values.len() < count {
+                return Err(line_error(&format!("{} needs {} numbers", keyword, count)));
+            }
+            Ok(values)
+        };
+        match keyword {
+            "v" => { let v = floats(3)?; positions.push([v[0], v[1], v[2]]); },
+            "vt" => { let v = floats(2)?; uvs.push([v[0], v[1]]); },
+            "vn" => { let v = floats(3)?; normals.push([v[0], v[1], v[2]]); },
+            "f" => {
+                let corners = parts.map(|corner| parse_corner(corner, positions.len(), uvs.len(), normals.len()))
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or_else(|| line_error("face refers to something that isn't there"))?;
+                if corners.len() < 3 {
+                    return Err(line_error("face has fewer than three corners"));
+                }
+                faces.push(corners);
+            },
+            // Comments, object and group names, materials and smoothing don't go in a model
+            _ => {}
+        }
+    }
+    if faces.is_empty() {
+        return Err("No faces to make a model out of".into());
+    }
+
+    let polygons = faces.iter().filter(|f| f.len() > 3).count();
+    if polygons > 0 {
+        log.push(format!("Split {} face{} with more than three corners into triangles",
+                         polygons, if polygons == 1 {""} else {"s"}));
+    }
+    // Every vertex in a model has texture coordinates and a normal, so it's all or nothing
+    let corners = || faces.iter().flat_map(|f| f.iter());
+    let has_uvs = corners().all(|c| c.1.is_some());
+    let has_normals = corners().all(|c| c.2.is_some());
+    if !has_uvs && corners().any(|c| c.1.is_some()) {
+        return Err("Some corners have texture coordinates and some don't, so there's nothing to give the rest".into());
+    }
+    if !has_normals && corners().any(|c| c.2.is_some()) {
+        return Err("Some corners have normals and some don't, so there's nothing to give the rest".into());
+    }
+    if !has_uvs {
+        log.push("No corner has texture coordinates, so they're all 0".to_string());
+    }
+
+    // Fan out each face into triangles of corners
+    let triangles: Vec<[Corner; 3]> = faces.iter()
+        .flat_map(|f| (1..f.len() - 1).map(move |i| [f[0], f[i], f[i + 1]]))
+        .collect();
+    let lined_up = corners().all(|&(p, t, n)| (!has_uvs || t == Some(p)) && (!has_normals || n == Some(p)))
+        && (!has_uvs || uvs.len() == positions.len())
+        && (!has_normals || normals.len() == positions.len());
+    let mut vertices = vec![];
+    let mut indices = vec![];
+    let make_vertex = |&(p, t, n): &Corner| Vertex {
+        position: positions[p],
+        uv: if has_uvs { uvs[t.unwrap()] } else { [0.0; 2] },
+        normal: if has_normals { normals[n.unwrap()] } else { [0.0; 3] }
+    };
+    if lined_up {
+        vertices = (0..positions.len()).map(|p| make_vertex(&(p, Some(p), Some(p)))).collect();
+        indices = triangles.iter().flat_map(|t| t.iter().map(|c| c.0 as u32)).collect();
+    } else {
+        let mut seen = HashMap::new();
+        for corner in triangles.iter().flat_map(|t| t.iter()) {
+            let index = *seen.entry(*corner).or_insert_with(|| {
+                vertices.push(make_vertex(corner));
+                vertices.len() - 1
+            });
+            indices.push(index as u32);
+        }
+    }
+    if vertices.len() > u32::MAX as usize || indices.len() > u32::MAX as usize {
+        return Err(format!("{} vertices and {} indices is too many for a model", vertices.len(), indices.len()).into());
+    }
+    if !has_normals {
+        log.push("No corner has a normal, so they've been worked out from the faces".to_string());
+        smooth_normals(&mut vertices, &indices);
+    }
+    Ok((Tex1Model { unknown: 0, vertices, indices, trailing: vec![] }, log))
+}
+
+/// Reads a mesh out of a glTF file: one mesh of triangles, with float positions and optionally
+/// float normals and texture coordinates, which is what export writes. Buffers can be embedded
+/// or in files next to `base_dir`. Anything else a vertex has is left out, since a model has
+/// nowhere to put it.
+pub fn read_gltf(text: &str, base_dir: &Path) -> Result<(Tex1Model, Vec<String>)> {
+    let gltf = Json::parse(text)?;
+    let mut log = vec![];
+    let meshes = gltf.get("meshes").and_then(Json::as_array).unwrap_or(&[]);
+    if meshes.len() != 1 {
+        return Err(format!("There are {} meshes, but a model is just the one", meshes.len()).into());
+    }
+    let primitives = meshes[0].get("primitives").and_then(Json::as_array).unwrap_or(&[]);
+    if primitives.len() != 1 {
+        return Err(format!("The mesh has {} primitives, but a model is just the one", primitives.len()).into());
+    }
+    let primitive = &primitives[0];
+    match primitive.get("mode").map(Json::as_usize) {
+        None | Some(Some(4)) => {},
+        _ => return Err("The mesh isn't made of triangles".into())
+    }
+    let attributes = primitive.get("attributes").and_then(Json::entries)
+        .ok_or("The mesh has no attributes")?;
+    let buffers = gltf.get("buffers").and_then(Json::as_array).unwrap_or(&[]).iter()
+        .enumerate()
+        .map(|(i, buffer)| load_buffer(buffer, base_dir).chain_err(|| format!("Failed to load buffer {}", i)))
+        .collect::<Result<Vec<_>>>()?;
+
+    let (mut positions, mut normals, mut uvs) = (None, None, None);
+    for (name, accessor) in attributes {
+        let accessor = accessor.as_usize().ok_or_else(|| format!("{} doesn't say which accessor it's in", name))?;
+        let read = |kind| -> Result<Vec<f64>> {
+            read_accessor(&gltf, &buffers, accessor, kind, &[FLOAT])
+                .chain_err(|| format!("Failed to read {}", name))
+        };
+        match name.as_str() {
+            "POSITION" => positions = Some(read("VEC3")?),
+            "NORMAL" => normals = Some(read("VEC3")?),
+            "TEXCOORD_0" => uvs = Some(read("VEC2")?),
+            _ => log.push(format!("Left out {}, since a model's vertices only have a position, texture coordinates and a normal", name))
+        }
+    }
+    let positions = positions.ok_or("The mesh has no POSITION")?;
+    let count = positions.len() / 3;
+    if normals.as_ref().is_some_and(|n| n.len() / 3 != count) || uvs.as_ref().is_some_and(|t| t.len() / 2 != count) {
+        return Err("The mesh's attributes don't all have the same number of vertices".into());
+    }
+    if uvs.is_none() {
+        log.push("No TEXCOORD_0, so texture coordinates are all 0".to_string());
+    }
+    let mut vertices: Vec<Vertex> = (0..count).map(|i| Vertex {
+        position: [positions[i * 3] as f32, positions[i * 3 + 1] as f32, positions[i * 3 + 2] as f32],
+        uv: uvs.as_ref().map_or([0.0; 2], |t| [t[i * 2] as f32, t[i * 2 + 1] as f32]),
+        normal: normals.as_ref().map_or([0.0; 3], |n| [n[i * 3] as f32, n[i * 3 + 1] as f32, n[i * 3 + 2] as f32])
+    }).collect();
+
+    let indices: Vec<u32> = match primitive.get("indices") {
+        Some(accessor) => {
+            let accessor = accessor.as_usize().ok_or("indices doesn't say which accessor they're in")?;
+            read_accessor(&gltf, &buffers, accessor, "SCALAR", &[UNSIGNED_BYTE, UNSIGNED_SHORT, UNSIGNED_INT])
+                .chain_err(|| "Failed to read the indices")?
+                .into_iter().map(|i| i as u32).collect()
+        },
+        None => (0..count as u32).collect()
+    };
+    if !indices.len().is_multiple_of(3) {
+        return Err(format!("There are {} indices, which isn't a whole number of triangles", indices.len()).into());
+    }
+    if let Some(bad) = indices.iter().find(|&&i| i as usize >= count) {
+        return Err(format!("There's an index of {}, but only {} vertices", bad, count).into());
+    }
+    if indices.is_empty() {
+        return Err("No triangles to make a model out of".into());
+    }
+    if normals.is_none() {
+        log.push("No NORMAL, so they've been worked out from the faces".to_string());
+        smooth_normals(&mut vertices, &indices);
+    }
+    Ok((Tex1Model { unknown: 0, vertices, indices, trailing: vec![] }, log))
+}
+
+// glTF's component types, which are OpenGL's
+const UNSIGNED_BYTE: usize = 5121;
+const UNSIGNED_SHORT: usize = 5123;
+const UNSIGNED_INT: usize = 5125;
+const FLOAT: usize = 5126;
+
+/// The bytes in a glTF buffer, from a base64 `data:` URI or a file next to the glTF.
+fn load_buffer(buffer: &Json, base_dir: &Path) -> Result<Vec<u8>> {
+    let uri = buffer.get("uri").and_then(Json::as_str)
+        .ok_or("It has no uri, which only happens in .glb files, and those aren't supported")?;
+    let data = if uri.starts_with("data:") {
+        let start = uri.find(";base64,").ok_or("Its data: URI isn't base64")? + ";base64,".len();
+        unbase64(&uri[start..]).ok_or("Its data: URI isn't valid base64")?
+    } else {
+        let path = base_dir.join(uri);
+        let mut data = vec![];
+        File::open(&path).and_then(|mut f| f.read_to_end(&mut data))
+            .chain_err(|| format!("Failed to read {}", path.display()))?;
+        data
+    };
+    let length = buffer.get("byteLength").and_then(Json::as_usize).ok_or("It has no byteLength")?;
+    if data.len() < length {
+        return Err(format!("It says it's {} bytes, but there's only {}", length, data.len()).into());
+    }
+    Ok(data)
+}
+
+/// Every number in accessor `index`, which has to be `kind` made of one of `component_types`.
+fn read_accessor(gltf: &Json, buffers: &[Vec<u8>], index: usize, kind: &str, component_types: &[usize]) -> Result<Vec<f64>> {
+    let accessor = gltf.get("accessors").and_then(Json::as_array).and_then(|a| a.get(index))
+        .ok_or_else(|| format!("There's no accessor {}", index))?;
+    let number = |json: &Json, key: &str| json.get(key).and_then(Json::as_usize);
+    if accessor.get("type").and_then(Json::as_str) != Some(kind) {
+        return Err(format!("Accessor {} should be a {}", index, kind).into());
+    }
+    let component_type = number(accessor, "componentType").unwrap_or(0);
+    if !component_types.contains(&component_type) {
+        return Err(format!("Accessor {} has component type {}, which doesn't fit in a model", index, component_type).into());
+    }
+    if accessor.get("sparse").is_some() {
+        return Err(format!("Accessor {} is sparse, which isn't supported", index).into());
+    }
+    let count = number(accessor, "count").ok_or_else(|| format!("Accessor {} has no count", index))?;
+    let view = number(accessor, "bufferView")
+        .and_then(|v| gltf.get("bufferViews").and_then(Json::as_array).and_then(|views| views.get(v)))
+        .ok_or_else(|| format!("Accessor {} has no buffer view", index))?;
+    let buffer = number(view, "buffer").and_then(|b| buffers.get(b))
+        .ok_or_else(|| format!("Accessor {}'s buffer view has no buffer", index))?;
+
+    let components = match kind { "SCALAR" => 1, "VEC2" => 2, _ => 3 };
+    let size = match component_type { UNSIGNED_BYTE => 1, UNSIGNED_SHORT => 2, _ => 4 };
+    let view_start = number(view, "byteOffset").unwrap_or(0);
+    let view_length = number(view, "byteLength").unwrap_or(0);
+    let start = number(accessor, "byteOffset").unwrap_or(0);
+    let stride = number(view, "byteStride").unwrap_or(components * size);
+    // Anything too big to add up can't fit in the buffer either
+    let end = match count.checked_sub(1) {
+        None => Some(start),
+        Some(last) => stride.checked_mul(last).and_then(|n| n.checked_add(start)).and_then(|n| n.checked_add(components * size))
+    };
+    let view_end = view_start.checked_add(view_length);
+    if end.is_none_or(|end| end > view_length) || view_end.is_none_or(|end| end > buffer.len()) {
+        return Err(format!("Accessor {} runs past the end of its buffer", index).into());
+    }
+    let data = &buffer[view_start..view_start + view_length];
+    Ok((0..count).flat_map(|i| (0..components).map(move |c| start + i * stride + c * size))
+        .map(|at| match component_type {
+            UNSIGNED_BYTE => data[at] as f64,
+            UNSIGNED_SHORT => u16::from_le_bytes([data[at], data[at + 1]]) as f64,
+            UNSIGNED_INT => u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]]) as f64,
+            _ => f32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]]) as f64
+        })
+        .collect())
+}
+
+/// Parses one corner of a face: `v`, `v/vt`, `v//vn` or `v/vt/vn`, counting from 1,
+/// or backwards from the end if negative. Returns None if any of them are out of range.
+fn parse_corner(corner: &str, positions: usize, uvs: usize, normals: usize) -> Option<Corner> {
+    let index = |part: &str, count: usize| -> Option<usize> {
+        let i = part.parse::<i64>().ok()?;
+        let i = if i < 0 { count as i64 + i } else { i - 1 };
+        if i >= 0 && (i as usize) < count { Some(i as usize) } else { None }
+    };
+    let mut parts = corner.split('/');
+    let position = index(parts.next()?, positions)?;
+    let uv = match parts.next() {
+        Some("") | None => None,
+        Some(part) => Some(index(part, uvs)?)
+    };
+    let normal = match parts.next() {
+        Some("") | None => None,
+        Some(part) => Some(index(part, normals)?)
+    };
+    Some((position, uv, normal))
+}
+
+/// Gives each vertex the average normal of the triangles it's in, weighted by their size.
+fn smooth_normals(vertices: &mut [Vertex], indices: &[u32]) {
+    let mut sums = vec![[0f32; 3]; vertices.len()];
+    for t in indices.chunks_exact(3) {
+        let (a, b, c) = (vertices[t[0] as usize].position, vertices[t[1] as usize].position, vertices[t[2] as usize].position);
+        let (u, v) = ([b[0] - a[0], b[1] - a[1], b[2] - a[2]], [c[0] - a[0], c[1] - a[1], c[2] - a[2]]);
+        let normal = [u[1] * v[2] - u[2] * v[1], u[2] * v[0] - u[0] * v[2], u[0] * v[1] - u[1] * v[0]];
+        for &i in t {
+            for axis in 0..3 {
+                sums[i as usize][axis] += normal[axis];
+            }
+        }
+    }
+    for (vertex, sum) in vertices.iter_mut().zip(sums) {
+        let length = (sum[0] * sum[0] + sum[1] * sum[1] + sum[2] * sum[2]).sqrt();
+        if length > 0.0 {
+            vertex.normal = [sum[0] / length, sum[1] / length, sum[2] / length];
+        }
+    }
+}
+
+/// Wavefront OBJ, with every vertex's position, texture coordinates and normal.
+fn to_obj(model: &Tex1Model, name: &str) -> String {
+    let mut obj = String::new();
+    writeln!(obj, "# Exported by deviltool from {}", name).unwrap();
+    writeln!(obj, "o {}", name).unwrap();
+    for v in model.vertices.iter() {
+        writeln!(obj, "v {} {} {}", v.position[0], v.position[1], v.position[2]).unwrap();
+    }
+    for v in model.vertices.iter() {
+        writeln!(obj, "vt {} {}", v.uv[0], v.uv[1]).unwrap();
+    }
+    for v in model.vertices.iter() {
+        writeln!(obj, "vn {} {} {}", v.normal[0], v.normal[1], v.normal[2]).unwrap();
+    }
+    // OBJ counts from 1, and every vertex has all three parts at the same index
+    for t in model.triangles() {
+        writeln!(obj, "f {0}/{0}/{0} {1}/{1}/{1} {2}/{2}/{2}", t[0] + 1, t[1] + 1, t[2] + 1).unwrap();
+    }
+    obj
+}
+
+/// glTF 2.0 with the buffer embedded in it, so it's the one file.
+fn to_gltf(model: &Tex1Model, name: &str) -> String {
+    // One buffer: positions, normals, texture coordinates, then indices
+    let mut buffer = vec![];
+    let mut views = vec![];
+    {
+        let mut add_view = |floats: &mut dyn Iterator<Item = f32>| {
+            let start = buffer.len();
+            for f in floats {
+                buffer.extend_from_slice(&f.to_le_bytes());
+            }
+            views.push((start, buffer.len() - start));
+        };
+        add_view(&mut model.vertices.iter().flat_map(|v| v.position.to_vec()));
+        add_view(&mut model.vertices.iter().flat_map(|v| v.normal.to_vec()));
+        add_view(&mut model.vertices.iter().flat_map(|v| v.uv.to_vec()));
+    }
+    let index_start = buffer.len();
+    for i in model.indices.iter() {
+        buffer.extend_from_slice(&i.to_le_bytes());
+    }
+    views.push((index_start, buffer.len() - index_start));
+
+    // glTF needs the bounds of the positions
+    let mut min = [f32::INFINITY; 3];
+    let mut max = [f32::NEG_INFINITY; 3];
+    for v in model.vertices.iter() {
+        for c in 0..3 {
+            min[c] = min[c].min(v.position[c]);
+            max[c] = max[c].max(v.position[c]);
+        }
+    }
+    let vertex_count = model.vertices.len();
+    let json_name = name.replace('\\', "\\\\").replace('"', "\\\"");
+    format!(r#"{{
+  "asset": {{"version": "2.0", "generator": "deviltool"}},
+  "scene": 0,
+  "scenes": [{{"nodes": [0]}}],
+  "nodes": [{{"mesh": 0, "name": "{name}"}}],
+  "meshes": [{{"name": "{name}", "primitives": [{{"attributes": {{"POSITION": 0, "NORMAL": 1, "TEXCOORD_0": 2}}, "indices": 3, "mode": 4}}]}}],
+  "accessors": [
+    {{"bufferView": 0, "componentType": 5126, "count": {vertices}, "type": "VEC3", "min": [{min}], "max": [{max}]}},
+    {{"bufferView": 1, "componentType": 5126, "count": {vertices}, "type": "VEC3"}},
+    {{"bufferView": 2, "componentType": 5126, "count": {vertices}, "type": "VEC2"}},
+    {{"bufferView": 3, "componentType": 5125, "count": {indices}, "type": "SCALAR"}}
+  ],
+  "bufferViews": [
+    {{"buffer": 0, "byteOffset": {}, "byteLength": {}, "target": 34962}},
+    {{"buffer": 0, "byteOffset": {}, "byteLength": {}, "target": 34962}},
+    {{"buffer": 0, "byteOffset": {}, "byteLength": {}, "target": 34962}},
+    {{"buffer": 0, "byteOffset": {}, "byteLength": {}, "target": 34963}}
+  ],
+  "buffers": [{{"byteLength": {length}, "uri": "data:application/octet-stream;base64,{data}"}}]
+}}
+"#,
+        views[0].0, views[0].1, views[1].0, views[1].1, views[2].0, views[2].1, views[3].0, views[3].1,
+        name = json_name,
+        vertices = vertex_count,
+        indices = model.indices.len(),
+        min = min.iter().map(|c| json_float(*c)).collect::<Vec<_>>().join(", "),
+        max = max.iter().map(|c| json_float(*c)).collect::<Vec<_>>().join(", "),
+        length = buffer.len(),
+        data = base64(&buffer))
+}
+
+/// JSON has no infinities or NaNs, so those (from an empty model) come out as 0.
+fn json_float(f: f32) -> String {
+    if f.is_finite() { format!("{:?}", f) } else { "0.0".to_string() }
+}
+
+/// Undoes `base64`. None if there's anything in `text` that isn't base64.
+fn unbase64(text: &str) -> Option<Vec<u8>> {
+    let mut data = Vec::with_capacity(text.len() / 4 * 3);
+    let (mut bits, mut bit_count) = (0u32, 0);
+    for c in text.bytes().take_while(|&c| c != b'=') {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None
+        };
+        bits = (bits << 6 | value as u32) & 0xffffff;
+        bit_count += 6;
+        if bit_count >= 8 {
+            bit_count -= 8;
+            data.push((bits >> bit_count) as u8);
+        }
+    }
+    Some(data)
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut text = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let bits = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                text.push(ALPHABET[(bits >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_round_trip() {
+        let data: Vec<u8> = (0..=255).collect();
+        for len in 0..8 {
+            assert_eq!(unbase64(&base64(&data[..len])).as_deref(), Some(&data[..len]));
+        }
+        assert_eq!(unbase64(&base64(&data)), Some(data));
+    }
+
+    #[test]
+    fn base64_known_values() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foob"), "Zm9vYg==");
+        assert_eq!(base64(&[0xfb, 0xff]), "+/8=");
+        assert_eq!(unbase64("Zm9vYmE="), Some(b"fooba".to_vec()));
+        assert_eq!(unbase64("Zm9vYmFy"), Some(b"foobar".to_vec()));
+        assert_eq!(unbase64("Zm9v YmFy"), None);
+        assert_eq!(unbase64("Zm9v-mFy"), None);
+    }
+}
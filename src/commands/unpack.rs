@@ -1,27 +1,65 @@
-
 use clap::ArgMatches;
 
 use std::io::prelude::*;
-use std::io::{BufReader, SeekFrom};
+use std::io::{self, BufReader, SeekFrom};
 use std::fs::{self, File};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use nom::IResult;
 use filetime::{self, FileTime};
+use image::ImageFormat;
 
 use super::super::types::*;
 use super::super::parser;
+use super::super::tex2;
+use super::imgconv;
+use super::jobs;
+use super::super::sidecar::Sidecar;
 use super::super::errors::*;
 
+/// Everything a worker needs to know to extract a single entry.
+struct UnpackJob {
+    file: DDSubFileHeader,
+    output_file: PathBuf
+}
+
+/// The parts of the command line that affect how each entry is written out.
+struct UnpackOptions {
+    preserve_glsl: bool,
+    modtimes: bool,
+    /// Format to convert textures to, and the extension that goes with it
+    convert_textures: Option<(ImageFormat, String)>,
+    texture_mipmaps: bool
+}
+
 pub fn execute(matches: &ArgMatches) -> Result<()> {
+    let jobs = jobs::job_count(matches)?;
+    let options = UnpackOptions {
+        preserve_glsl: matches.is_present("preserveglsl"),
+        modtimes: !matches.is_present("modtimes"),
+        convert_textures: if matches.is_present("converttextures") {
+            let ext = matches.value_of("textureformat").unwrap_or("png");
+            Some((imgconv::image_format(ext).unwrap(), ext.to_string()))
+        } else {
+            None
+        },
+        texture_mipmaps: matches.is_present("texturemipmaps")
+    };
+
     // make sure we have somewhere to put the files
-    let mut output_dir = PathBuf::from(matches.value_of("FOLDER").unwrap());
-    fs::create_dir_all(output_dir.clone()).chain_err(|| "Failed to create output directory")?;
+    let base_dir = PathBuf::from(matches.value_of("FOLDER").unwrap());
+    fs::create_dir_all(base_dir.clone()).chain_err(|| "Failed to create output directory")?;
+    let mut output_dir = base_dir.clone();
 
-    let f = File::open(matches.value_of("FILE").unwrap()).unwrap();
+    let archive_path = PathBuf::from(matches.value_of("FILE").unwrap());
+    let f = File::open(&archive_path).chain_err(|| "Failed to open archive")?;
     let mut reader = BufReader::new(f);
-    let mut firstfolder = true;
-    let (_, mut files) = parser::read_header(&mut reader).unwrap().unwrap(); //TODO this
+    let (_, mut files) = parser::read_header(&mut reader)
+        .chain_err(|| "Failed to read archive header")?
+        .map_err(|e| format!("Failed to parse archive header: {:?}", e))?;
 
+    // Work out where everything goes before extracting anything,
+    // since folder markers affect every entry that comes after them.
+    let mut plan: Vec<UnpackJob> = Vec::with_capacity(files.len());
     if !matches.is_present("nofolders") { files.reverse(); }
     for file in files {
         if file.file_type == DDFiletype::FolderMarker {
@@ -31,12 +69,8 @@ pub fn execute(matches: &ArgMatches) -> Result<()> {
                 println!("Ignoring folder marker {}", file.filename);
                 continue;
             } else {
-                if !firstfolder {
-                    output_dir.pop();
-                } else {
-                    firstfolder = false;
-                }
-                output_dir.push(file.filename.clone());
+                // Nested folders are marked with their whole path, so always start from the top
+                output_dir = base_dir.join(file.filename.clone());
                 fs::create_dir_all(output_dir.clone())
                     .chain_err(|| "Failed to create subdirectory")?;
                 continue;
@@ -44,58 +78,112 @@ pub fn execute(matches: &ArgMatches) -> Result<()> {
         }
         let mut output_file = output_dir.join(file.filename.clone());
         output_file.set_extension(file.file_type.extension());
+        plan.push(UnpackJob { file, output_file });
+    }
 
-        {
-            reader.seek(SeekFrom::Start(file.offset as u64))
-                .chain_err(|| "Failed to seek to a position within archive")?;
-            let mut buf = vec![0; file.size as usize];
-            reader.read_exact(&mut buf[..])
-                .chain_err(|| "Failed to read file from archive")?;
-
-            if file.file_type == DDFiletype::GLSL && !matches.is_present("preserveglsl") {
-                match parser::glsl_file(buf.as_ref()) {
-                    IResult::Incomplete(_) | IResult::Error(_) => {
-                        println!("Malformed GLSL file! Saving as normal file");
-                    },
-                    IResult::Done(_, (name, vertex, fragment)) => {
-                        if name != file.filename {
-                            println!("Warning: GLSL name is {} but saving as {}",
-                                     name, file.filename);
+    // Hand the entries out to the workers, each of which reads through its own file handle.
+    let readers = (0..jobs.min(plan.len()))
+        .map(|_| File::open(&archive_path).map(BufReader::new))
+        .collect::<io::Result<Vec<_>>>()
+        .chain_err(|| "Failed to open archive")?;
+    jobs::run_jobs(&plan, readers, "extract",
+                   |job| job.output_file.display().to_string(),
+                   |reader, job| extract_entry(reader, job, &options))
+}
+
+/// Extracts a single entry, returning the lines it wants printed.
+fn extract_entry<R: Read + Seek>(reader: &mut R, job: &UnpackJob, options: &UnpackOptions) -> Result<Vec<String>> {
+    let file = &job.file;
+    let mut output_file = job.output_file.clone();
+    let mut log = vec![];
+
+    // Nested folder markers are named with their path, even with --foldermarkers
+    if let Some(parent) = output_file.parent() {
+        fs::create_dir_all(parent)
+            .chain_err(|| format!("Failed to create directory {}", parent.display()))?;
+    }
+
+    reader.seek(SeekFrom::Start(file.offset as u64))
+        .chain_err(|| "Failed to seek to a position within archive")?;
+    let mut buf = vec![0; file.size as usize];
+    reader.read_exact(&mut buf[..])
+        .chain_err(|| "Failed to read file from archive")?;
+
+    if file.file_type == DDFiletype::GLSL && !options.preserve_glsl {
+        match parser::glsl_file(buf.as_ref()) {
+            IResult::Incomplete(_) | IResult::Error(_) => {
+                log.push("Malformed GLSL file! Saving as normal file".to_string());
+            },
+            IResult::Done(_, (name, vertex, fragment)) => {
+                output_file.set_extension("vert");
+                if name != file.filename {
+                    // Keep the real name around so pack can put it back
+                    log.push(format!("GLSL name is {} but saving as {}, keeping the name in {}",
+                                     name, file.filename, Sidecar::path_for(&output_file).display()));
+                    let mut sidecar = Sidecar::new();
+                    sidecar.set("name", name);
+                    sidecar.save_for(&output_file)?;
+                }
+                log.push(format!("Writing {}", output_file.display()));
+                let mut file_handle = File::create(output_file.clone())
+                    .chain_err(|| "Failed to open GLSL vertex shader file")?;
+                file_handle.write_all(vertex.as_bytes())
+                    .chain_err(|| "Failed to save GLSL vertex shader file")?;
+
+                output_file.set_extension("frag");
+                log.push(format!("Writing {}", output_file.display()));
+                let mut file_handle = File::create(output_file.clone())
+                    .chain_err(|| "Failed to open GLSL fragment shader file")?;
+                file_handle.write_all(fragment.as_bytes())
+                    .chain_err(|| "Failed to save GLSL fragment shader file")?;
+                return Ok(log);
+            }
+        }
+    }
+
+    if file.file_type == DDFiletype::Texture2 {
+        if let Some((format, ref ext)) = options.convert_textures {
+            match tex2::DDTex2Image::read(&buf) {
+                Ok(tex2image) => {
+                    output_file.set_extension(ext);
+                    let (written, warnings) = imgconv::save_levels(&tex2image, &output_file, format, options.texture_mipmaps,
+                                                                   &imgconv::PixelOptions::default())
+                        .chain_err(|| format!("Failed to save texture to {}", output_file.display()))?;
+                    for warning in warnings {
+                        log.push(format!("Warning: {}: {}", file.filename, warning));
+                    }
+                    for written_file in written {
+                        log.push(format!("Writing {}", written_file.display()));
+                        if options.modtimes && file.timestamp != 0 {
+                            set_timestamp(&written_file, file.timestamp)?;
                         }
-                        output_file.set_extension("vert");
-                        println!("Writing {}", output_file.display());
-                        let mut file_handle = File::create(output_file.clone())
-                            .chain_err(|| "Failed to open GLSL vertex shader file")?;
-                        file_handle.write_all(vertex.as_bytes())
-                            .chain_err(|| "Failed to save GLSL vertex shader file")?;
-
-                        output_file.set_extension("frag");
-                        println!("Writing {}", output_file.display());
-                        let mut file_handle = File::create(output_file.clone())
-                            .chain_err(|| "Failed to open GLSL fragment shader file")?;
-                        file_handle.write_all(fragment.as_bytes())
-                            .chain_err(|| "Failed to save GLSL fragment shader file")?;
-                        continue;
                     }
+                    return Ok(log);
+                },
+                Err(e) => {
+                    log.push(format!("Warning: couldn't convert texture {} ({}), saving it as-is", file.filename, e));
                 }
             }
-
-            println!("Writing {}", output_file.display());
-            let mut file_handle = File::create(output_file.clone())
-                .chain_err(|| format!("Failed to open output file {}", output_file.display()))?;
-            file_handle.write_all(buf.as_mut())
-                .chain_err(|| format!("Failed to save to output file {}", output_file.display()))?;
-
         }
+    }
 
-        if !matches.is_present("modtimes") && file.timestamp != 0 {
-            let metadata = fs::metadata(output_file.clone()).unwrap();
-            filetime::set_file_times(output_file.clone(),
-                                     FileTime::from_last_access_time(&metadata),
-                                     FileTime::from_seconds_since_1970(file.timestamp as u64, 0))
-                      .chain_err(|| format!("Failed to set timestamp on file {}", output_file.display()))?;
-        }
+    log.push(format!("Writing {}", output_file.display()));
+    let mut file_handle = File::create(output_file.clone())
+        .chain_err(|| format!("Failed to open output file {}", output_file.display()))?;
+    file_handle.write_all(buf.as_mut())
+        .chain_err(|| format!("Failed to save to output file {}", output_file.display()))?;
 
+    if options.modtimes && file.timestamp != 0 {
+        set_timestamp(&output_file, file.timestamp)?;
     }
-    Ok(())
-}
\ No newline at end of file
+    Ok(log)
+}
+
+fn set_timestamp(output_file: &Path, timestamp: u32) -> Result<()> {
+    let metadata = fs::metadata(output_file)
+        .chain_err(|| format!("Failed to read metadata of file {}", output_file.display()))?;
+    filetime::set_file_times(output_file,
+                             FileTime::from_last_access_time(&metadata),
+                             FileTime::from_seconds_since_1970(timestamp as u64, 0))
+              .chain_err(|| format!("Failed to set timestamp on file {}", output_file.display()))
+}
@@ -0,0 +1,216 @@
+use clap::ArgMatches;
+use image::{imageops, GenericImageView, Pixel, Rgba, RgbaImage};
+
+use std::collections::BTreeSet;
+use std::env;
+use std::fmt::Write as FmtWrite;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use super::super::archive::MappedArchive;
+use super::super::tex2::DDTex2Image;
+use super::super::types::DDFiletype;
+use super::super::errors::*;
+use super::imgconv;
+
+/// What transparent pixels are drawn on top of, since terminals don't do transparency.
+const BACKGROUND: Rgba<u8> = Rgba([0, 0, 0, 255]);
+
+pub fn execute(matches: &ArgMatches) -> Result<()> {
+    let file = PathBuf::from(matches.value_of("FILE").unwrap());
+    let (name, texture) = match matches.value_of("ENTRY") {
+        Some(entry_name) => {
+            let archive = MappedArchive::open(&file)?;
+            let entry = archive.find(entry_name, DDFiletype::Texture2)
+                .ok_or_else(|| format!("{} has no texture called {}", file.display(), entry_name))?;
+            let texture = DDTex2Image::read(archive.data(&entry))
+                .chain_err(|| format!("Failed to decode {}", entry_name))?;
+            (entry_name.to_string(), texture)
+        },
+        None => (file.display().to_string(), imgconv::read_tex2(&file)?)
+    };
+
+    let sixel = matches.value_of("mode") == Some("sixel");
+    // Half blocks are one character per pixel across, so fit them in the terminal.
+    // Sixels are real pixels, so they're left alone unless asked.
+    let max_width = match matches.value_of("width") {
+        Some(width) => Some(width.parse::<u32>().ok().filter(|&w| w > 0)
+            .ok_or_else(|| format!("Invalid width {}", width))?),
+        None if sixel => None,
+        None => Some(env::var("COLUMNS").ok().and_then(|c| c.parse().ok()).unwrap_or(80))
+    };
+    let level = match matches.value_of("level") {
+        Some(level) => Some(level.parse::<u8>().ok().filter(|&l| l < texture.mipmap_levels.max(1))
+            .ok_or_else(|| format!("Invalid mipmap level {}, {} has {}", level, name, texture.mipmap_levels.max(1)))?),
+        None => None
+    };
+
+    let (level, image) = fit(&texture, level, max_width)?;
+    let output = if sixel { render_sixel(&image) } else { render_halfblock(&image) };
+    let (width, height) = texture.level(level)?.dimensions();
+    let shrunk = if image.dimensions() != (width, height) {
+        format!(", shown at {}x{}", image.width(), image.height())
+    } else {
+        String::new()
+    };
+    println!("{}: {}x{}, level {} of {}{}", name, width, height, level, texture.mipmap_levels.max(1), shrunk);
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    stdout.write_all(output.as_bytes())
+        .and_then(|_| stdout.flush())
+        .chain_err(|| "Failed to write to the terminal")?;
+    Ok(())
+}
+
+/// Picks out the level to show and shrinks it down to `max_width` if it's too wide.
+///
+/// Without a particular level to show, the biggest one that fits is used.
+fn fit(texture: &DDTex2Image, level: Option<u8>, max_width: Option<u32>) -> Result<(u8, RgbaImage)> {
+    let levels = texture.mipmap_levels.max(1);
+    let level = match (level, max_width) {
+        (Some(level), _) => level,
+        (None, Some(max_width)) => (0..levels)
+            .find(|&n| texture.level(n).map(|l| l.width() <= max_width).unwrap_or(false))
+            .unwrap_or(levels - 1),
+        (None, None) => 0
+    };
+    let image = texture.level(level)?.to_image();
+    let image = match max_width {
+        Some(max_width) if image.width() > max_width => {
+            let height = ((image.height() as u64 * max_width as u64) / image.width() as u64).max(1) as u32;
+            imageops::resize(&image, max_width, height, imageops::FilterType::Triangle)
+        },
+        _ => image
+    };
+    Ok((level, image))
+}
+
+fn flatten(pixel: Rgba<u8>) -> Rgba<u8> {
+    let mut flat = BACKGROUND;
+    flat.blend(&pixel);
+    flat
+}
+
+/// Draws an image with `▀`, two pixels to a character: the top one in the foreground colour
+/// and the bottom one in the background, using 24-bit colour escapes.
+pub fn render_halfblock(image: &RgbaImage) -> String {
+    let mut output = String::new();
+    for y in (0..image.height()).step_by(2) {
+        for x in 0..image.width() {
+            let top = flatten(*image.get_pixel(x, y));
+            write!(output, "\x1b[38;2;{};{};{}m", top[0], top[1], top[2]).unwrap();
+            if y + 1 < image.height() {
+                let bottom = flatten(*image.get_pixel(x, y + 1));
+                write!(output, "\x1b[48;2;{};{};{}m", bottom[0], bottom[1], bottom[2]).unwrap();
+            } else {
+                output.push_str("\x1b[49m");
+            }
+            output.push('▀');
+        }
+        output.push_str("\x1b[0m\n");
+    }
+    output
+}
+
+/// Draws an image as sixels, with the colours cut down to a 6x6x6 cube so they fit in the palette.
+pub fn render_sixel(image: &RgbaImage) -> String {
+    // Each channel gets 6 levels, 0 to 5
+    let colour_of = |x: u32, y: u32| -> usize {
+        let pixel = flatten(*image.get_pixel(x, y));
+        let level = |c: u8| (c as usize * 5 + 127) / 255;
+        level(pixel[0]) * 36 + level(pixel[1]) * 6 + level(pixel[2])
+    };
+    let (width, height) = image.dimensions();
+
+    let mut output = String::new();
+    write!(output, "\x1bPq\"1;1;{};{}", width, height).unwrap();
+    let mut used = BTreeSet::new();
+    for y in 0..height {
+        for x in 0..width {
+            used.insert(colour_of(x, y));
+        }
+    }
+    for &colour in used.iter() {
+        // Sixel colours are percentages
+        write!(output, "#{};2;{};{};{}", colour, colour / 36 * 20, colour / 6 % 6 * 20, colour % 6 * 20).unwrap();
+    }
+
+    // Each band is 6 pixels tall, drawn once for each colour in it
+    for top in (0..height).step_by(6) {
+        let rows = (height - top).min(6);
+        let mut band_colours = BTreeSet::new();
+        for y in top..top + rows {
+            for x in 0..width {
+                band_colours.insert(colour_of(x, y));
+            }
+        }
+        for (i, &colour) in band_colours.iter().enumerate() {
+            if i != 0 {
+                // Back to the start of the band for the next colour
+                output.push('$');
+            }
+            write!(output, "#{}", colour).unwrap();
+            let sixels: Vec<u8> = (0..width).map(|x| {
+                (0..rows).filter(|&r| colour_of(x, top + r) == colour)
+                    .fold(0u8, |bits, r| bits | (1 << r)) + 63
+            }).collect();
+            push_run_length(&mut output, &sixels);
+        }
+        output.push('-');
+    }
+    output.push_str("\x1b\\\n");
+    output
+}
+
+/// Adds sixels to the output, squashing runs of the same one into `!count` form.
+fn push_run_length(output: &mut String, sixels: &[u8]) {
+    let mut i = 0;
+    while i < sixels.len() {
+        let run = sixels[i..].iter().take_while(|&&s| s == sixels[i]).count();
+        if run > 3 {
+            write!(output, "!{}{}", run, sixels[i] as char).unwrap();
+        } else {
+            for _ in 0..run { output.push(sixels[i] as char); }
+        }
+        i += run;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2x3, so the last row of half blocks has nothing underneath,
+    /// with a transparent pixel that should come out as the background.
+    fn test_image() -> RgbaImage {
+        let pixels = [
+            [255, 0, 0, 255], [0, 255, 0, 255],
+            [0, 0, 255, 255], [255, 255, 255, 0],
+            [255, 255, 255, 255], [128, 128, 128, 255]
+        ];
+        RgbaImage::from_fn(2, 3, |x, y| Rgba(pixels[(y * 2 + x) as usize]))
+    }
+
+    #[test]
+    fn halfblock() {
+        assert_eq!(render_halfblock(&test_image()), concat!(
+            "\x1b[38;2;255;0;0m\x1b[48;2;0;0;255m▀\x1b[38;2;0;255;0m\x1b[48;2;0;0;0m▀\x1b[0m\n",
+            "\x1b[38;2;255;255;255m\x1b[49m▀\x1b[38;2;128;128;128m\x1b[49m▀\x1b[0m\n"));
+    }
+
+    #[test]
+    fn sixel() {
+        assert_eq!(render_sixel(&test_image()), concat!(
+            "\x1bPq\"1;1;2;3",
+            "#0;2;0;0;0#5;2;0;0;100#30;2;0;100;0#129;2;60;60;60#180;2;100;0;0#215;2;100;100;100",
+            "#0?A$#5A?$#30?@$#129?C$#180@?$#215C?-",
+            "\x1b\\\n"));
+    }
+
+    #[test]
+    fn sixel_run_length() {
+        let mut output = String::new();
+        push_run_length(&mut output, b"?????@@@A");
+        assert_eq!(output, "!5?@@@A");
+    }
+}
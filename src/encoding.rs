@@ -0,0 +1,41 @@
+/// A 64-bit FNV-1a hash, for telling whether a file came back byte-for-byte the same.
+pub fn checksum(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, &b| (hash ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+/// Bytes as lowercase hex, two digits each, for putting in a sidecar.
+pub fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Undoes `to_hex`. None if `text` isn't an even number of hex digits.
+pub fn from_hex(text: &str) -> Option<Vec<u8>> {
+    // Checked up front, since from_str_radix would let a leading + through
+    if !text.bytes().all(|b| b.is_ascii_hexdigit()) || !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex() {
+        assert_eq!(to_hex(&[0x00, 0xde, 0xad, 0x0f]), "00dead0f");
+        assert_eq!(from_hex("00DEad0f"), Some(vec![0x00, 0xde, 0xad, 0x0f]));
+        assert_eq!(from_hex(""), Some(vec![]));
+        assert_eq!(from_hex("abc"), None);
+        assert_eq!(from_hex("zz"), None);
+        assert_eq!(from_hex("+1"), None);
+        assert_eq!(from_hex("é0"), None);
+    }
+
+    #[test]
+    fn fnv1a() {
+        assert_eq!(checksum(b""), 0xcbf29ce484222325);
+        assert_eq!(checksum(b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(checksum(b"foobar"), 0x85944171f73967e8);
+    }
+}
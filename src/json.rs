@@ -0,0 +1,231 @@
+use std::char;
+use std::iter::Peekable;
+use std::str::Chars;
+
+use errors::*;
+
+/// How deep arrays and objects can nest. glTF never gets near this, and without a limit
+/// a file of nothing but `[` would run the parser out of stack.
+const MAX_DEPTH: usize = 128;
+
+/// Just enough JSON to read glTF files.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    /// Keys and values, in the order they were read
+    Object(Vec<(String, Json)>)
+}
+
+impl Json {
+    pub fn parse(text: &str) -> Result<Json> {
+        let mut chars = text.chars().peekable();
+        let value = parse_value(&mut chars, 0)?;
+        skip_whitespace(&mut chars);
+        match chars.next() {
+            None => Ok(value),
+            Some(c) => Err(format!("Unexpected {:?} after the end of the JSON", c).into())
+        }
+    }
+
+    /// The value for `key`, if this is an object that has one.
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match *self {
+            Json::Object(ref values) => values.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Json::Number(n) => Some(n),
+            _ => None
+        }
+    }
+
+    /// The number, if it's a whole one that fits in a `usize`.
+    pub fn as_usize(&self) -> Option<usize> {
+        self.as_f64().filter(|n| n.fract() == 0.0 && *n >= 0.0 && *n <= usize::MAX as f64).map(|n| n as usize)
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Json::String(ref s) => Some(s),
+            _ => None
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match *self {
+            Json::Array(ref values) => Some(values),
+            _ => None
+        }
+    }
+
+    /// Keys and values, if this is an object.
+    pub fn entries(&self) -> Option<&[(String, Json)]> {
+        match *self {
+            Json::Object(ref values) => Some(values),
+            _ => None
+        }
+    }
+}
+
+type Input<'a> = Peekable<Chars<'a>>;
+
+fn skip_whitespace(chars: &mut Input) {
+    while chars.peek().is_some_and(|c| c.is_whitespace()) {
+        chars.next();
+    }
+}
+
+fn expect(chars: &mut Input, expected: char) -> Result<()> {
+    skip_whitespace(chars);
+    match chars.next() {
+        Some(c) if c == expected => Ok(()),
+        Some(c) => Err(format!("Expected {:?} in JSON, found {:?}", expected, c).into()),
+        None => Err(format!("Expected {:?} in JSON, but it ended", expected).into())
+    }
+}
+
+fn parse_value(chars: &mut Input, depth: usize) -> Result<Json> {
+    skip_whitespace(chars);
+    if depth >= MAX_DEPTH && chars.peek().is_some_and(|&c| c == '{' || c == '[') {
+        return Err(format!("JSON nests more than {} deep", MAX_DEPTH).into());
+    }
+    match chars.peek().cloned() {
+        Some('{') => {
+            chars.next();
+            let mut values = vec![];
+            skip_whitespace(chars);
+            if chars.peek() == Some(&'}') {
+                chars.next();
+                return Ok(Json::Object(values));
+            }
+            loop {
+                skip_whitespace(chars);
+                let key = parse_string(chars)?;
+                expect(chars, ':')?;
+                values.push((key, parse_value(chars, depth + 1)?));
+                skip_whitespace(chars);
+                match chars.next() {
+                    Some(',') => continue,
+                    Some('}') => return Ok(Json::Object(values)),
+                    _ => return Err("Expected , or } in a JSON object".into())
+                }
+            }
+        },
+        Some('[') => {
+            chars.next();
+            let mut values = vec![];
+            skip_whitespace(chars);
+            if chars.peek() == Some(&']') {
+                chars.next();
+                return Ok(Json::Array(values));
+            }
+            loop {
+                values.push(parse_value(chars, depth + 1)?);
+                skip_whitespace(chars);
+                match chars.next() {
+                    Some(',') => continue,
+                    Some(']') => return Ok(Json::Array(values)),
+                    _ => return Err("Expected , or ] in a JSON array".into())
+                }
+            }
+        },
+        Some('"') => parse_string(chars).map(Json::String),
+        Some(c) if c == '-' || c.is_ascii_digit() => {
+            let mut number = String::new();
+            while chars.peek().is_some_and(|&c| c.is_ascii_digit() || "+-.eE".contains(c)) {
+                number.push(chars.next().unwrap());
+            }
+            number.parse().map(Json::Number).chain_err(|| format!("{} isn't a number", number))
+        },
+        Some(_) => {
+            let mut word = String::new();
+            while chars.peek().is_some_and(|c| c.is_ascii_alphabetic()) {
+                word.push(chars.next().unwrap());
+            }
+            match word.as_str() {
+                "true" => Ok(Json::Bool(true)),
+                "false" => Ok(Json::Bool(false)),
+                "null" => Ok(Json::Null),
+                _ => Err(format!("Unexpected {:?} in JSON", word).into())
+            }
+        },
+        None => Err("JSON ended too early".into())
+    }
+}
+
+fn parse_string(chars: &mut Input) -> Result<String> {
+    expect(chars, '"')?;
+    let mut s = String::new();
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(s),
+            Some('\\') => match chars.next() {
+                Some('n') => s.push('\n'),
+                Some('t') => s.push('\t'),
+                Some('r') => s.push('\r'),
+                Some('b') => s.push('\u{8}'),
+                Some('f') => s.push('\u{c}'),
+                Some('u') => {
+                    let hex: String = chars.by_ref().take(4).collect();
+                    let code = u32::from_str_radix(&hex, 16)
+                        .chain_err(|| format!("\\u{} isn't a character", hex))?;
+                    // Surrogate pairs don't come up in glTF, so they just turn into replacement characters
+                    s.push(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER));
+                },
+                Some(c) => s.push(c),
+                None => return Err("JSON string ended too early".into())
+            },
+            Some(c) => s.push(c),
+            None => return Err("JSON string ended too early".into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values() {
+        let json = Json::parse(r#" {"a": [1, -2.5e1, true, false, null], "b": {}, "c": "x\"\n\u00e9", "a": []} "#).unwrap();
+        assert_eq!(json.get("a"), Some(&Json::Array(vec![
+            Json::Number(1.0), Json::Number(-25.0), Json::Bool(true), Json::Bool(false), Json::Null
+        ])));
+        assert_eq!(json.get("b"), Some(&Json::Object(vec![])));
+        assert_eq!(json.get("c").and_then(Json::as_str), Some("x\"\n\u{e9}"));
+        assert_eq!(json.get("d"), None);
+        assert_eq!(json.entries().map(|e| e.len()), Some(4));
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(Json::parse("3").unwrap().as_usize(), Some(3));
+        assert_eq!(Json::parse("3.5").unwrap().as_usize(), None);
+        assert_eq!(Json::parse("-3").unwrap().as_usize(), None);
+        assert_eq!(Json::parse("1e400").unwrap().as_usize(), None);
+        assert!(Json::parse("1.2.3").is_err());
+    }
+
+    #[test]
+    fn malformed() {
+        for text in &["", "[1,", "[1 2]", "{\"a\" 1}", "{1: 2}", "\"abc", "nope", "[] []", "\"\\uzz\""] {
+            assert!(Json::parse(text).is_err(), "{:?} should be an error", text);
+        }
+    }
+
+    #[test]
+    fn nesting() {
+        let nested = |depth| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+        assert!(Json::parse(&nested(MAX_DEPTH)).is_ok());
+        assert!(Json::parse(&nested(MAX_DEPTH + 1)).is_err());
+        assert!(Json::parse(&"[".repeat(200000)).is_err());
+        assert!(Json::parse(&"{\"a\":".repeat(200000)).is_err());
+    }
+}
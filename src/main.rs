@@ -9,10 +9,18 @@ extern crate filetime;
 extern crate image;
 extern crate byteorder;
 extern crate bytesize;
+extern crate memmap;
 
+pub mod archive;
+pub mod encoding;
+pub mod font;
+pub mod json;
 pub mod parser;
+pub mod sidecar;
+pub mod tex1;
 pub mod tex2;
 pub mod types;
+pub mod wireframe;
 mod commands;
 
 mod errors {
@@ -70,21 +78,123 @@ fn run() -> Result<()> {
             (@arg nofolders: -f --nofolders "Don't automatically create subfolders for output")
             (@arg foldermarkers: -k --foldermarkers "Export .foldermarker files instead of folders")
             (@arg preserveglsl: -g --preserveglsl "Don't split GLSL shaders into their respective files")
+            (@arg jobs: -j --jobs +takes_value "Number of files to extract at once (default 1)")
+            (@arg converttextures: --("convert-textures") "Convert textures to png (or --texture-format) instead of saving them as dd_tex2")
+            (@arg textureformat: --("texture-format") +takes_value requires[converttextures]
+                possible_values(&["png", "bmp", "tga", "tiff", "jpeg", "ico", "pnm"])
+                "With --convert-textures: image format to convert textures to (default png)")
+            (@arg texturemipmaps: --("texture-mipmaps") requires[converttextures]
+                "With --convert-textures: export mipmaps as well as the full-resolution image")
         )
         (@subcommand imgconv =>
-            (about: "Convert images from dd_tex2 to png")
+            (about: "Convert images from dd_tex2 to png (or bmp, tga, tiff, jpeg, ico, pnm), or back again")
             (@setting ArgRequiredElseHelp)
-            (@arg FILE: +required {file_still_really_exists} "File to convert")
-            (@arg OUTFILE: "File to output to")
-            (@arg mipmaps: -m --mipmaps "Export mipmaps as well as the full-resolution image")
-            //(@arg reverse: -r --reverse "Convert to tex2. Yes this is awkward.")
+            (@arg FILE: +required {file_still_really_exists} "File to convert, or a directory or archive to convert every texture in")
+            (@arg OUTFILE: "File to output to (directory, when converting a directory or archive)")
+            (@arg mipmaps: -m --mipmaps "Export mipmaps as well as the full-resolution image\nWith --reverse: generate a full set of mipmaps")
+            (@arg reverse: -r --reverse "Convert to tex2. Yes this is awkward.\nAnything not given on the command line comes from FILE's .ddmeta sidecar")
+            (@arg filter: --filter +takes_value possible_values(&["box", "triangle", "lanczos"])
+                "With --reverse --mipmaps: how to shrink each mipmap level (default box)")
+            (@arg mipmapfiles: --mipmapfiles
+                "With --reverse --mipmaps: use FILE_1.png, FILE_2.png, etc for mipmap levels when they exist")
+            (@arg format: -f --format +takes_value possible_values(&["png", "bmp", "tga", "tiff", "jpeg", "ico", "pnm"])
+                "Image format to write, instead of going by OUTFILE's extension\nWith --reverse: format FILE is in")
+            (@arg jobs: -j --jobs +takes_value "With a directory or archive: number of textures to convert at once (default 1)")
+            (@arg swizzle: --swizzle +takes_value possible_values(&["rgba", "bgra", "argb"])
+                "Order the texture's channels are stored in (default rgba)")
+            (@arg premultiplied: --premultiplied
+                "The texture's colours are premultiplied by alpha: undo it when exporting, apply it with --reverse")
+            (@arg splitalpha: --("split-alpha")
+                "Save alpha as a separate greyscale image, FILE_alpha.png, and leave it out of the main one")
+            (@arg alpha: --alpha +takes_value {file_still_really_exists}
+                "With --reverse: take alpha from this greyscale image, which has to be the same size as FILE")
+            (@arg matchoriginal: --("match-original")
+                "With --reverse: resize FILE to the size of the texture it's replacing, and give it as many mipmap levels\nThe texture is the one FILE's sidecar says it came from, or --original")
+            (@arg original: --original +takes_value {file_still_really_exists}
+                "With --reverse: texture FILE is replacing, implies --match-original")
+            (@arg resize: --resize +takes_value possible_values(&["fit", "fill", "stretch"])
+                "With --match-original: fit inside the texture, fill it and crop, or stretch to it (default fit)")
+        )
+        (@subcommand imgdiff =>
+            (about: "Compare two textures level by level, or a texture and an image")
+            (@setting ArgRequiredElseHelp)
+            (@arg A: +required {file_still_really_exists} "Texture or image to compare")
+            (@arg B: +required {file_still_really_exists} "Texture or image to compare it to")
+            (@arg output: -o --output +takes_value "Save an image of where the full-size levels differ, in red")
+            (@arg tolerance: -t --tolerance +takes_value
+                "Biggest difference in any channel that still counts as a match (default 0)\nAnything above it exits with an error")
+        )
+        (@subcommand sheet =>
+            (about: "Lay out every texture in an archive on one labelled image")
+            (@setting ArgRequiredElseHelp)
+            (@arg FILE: +required {file_still_really_exists} "Archive to get textures from")
+            (@arg output: -o --output +takes_value "Image to save the sheet to (default FILE_sheet.png)")
+            (@arg cellsize: -c --cellsize +takes_value "Biggest a texture is drawn, in pixels (default 128)")
+            (@arg columns: --columns +takes_value "How many textures go across (default enough to make a square)")
+            (@arg sort: -s --sort +takes_value possible_values(&["name", "size", "archive"])
+                "What order the textures go in (default name)")
+        )
+        (@subcommand view =>
+            (about: "Show a texture in the terminal")
+            (@setting ArgRequiredElseHelp)
+            (@arg FILE: +required {file_still_really_exists} "Texture to show, or an archive to show one from")
+            (@arg ENTRY: "Texture in the archive to show, by name (folder/name if it's in a folder)")
+            (@arg level: -l --level +takes_value "Mipmap level to show (default the biggest that fits)")
+            (@arg mode: -m --mode +takes_value possible_values(&["halfblock", "sixel"])
+                "How to draw it: truecolor half blocks, or sixels for terminals that can (default halfblock)")
+            (@arg width: -w --width +takes_value
+                "Widest it can be, in characters or sixel pixels (default $COLUMNS or 80 for half blocks, no limit for sixels)")
+        )
+        (@subcommand tex1 =>
+            (about: "Work with dd_tex1 files, which are probably models")
+            (@setting ArgRequiredElseHelp)
+            (@subcommand export =>
+                (about: "Export a model to OBJ or glTF, going by the best guess at the layout")
+                (@setting ArgRequiredElseHelp)
+                (@arg FILE: +required {file_still_really_exists} "Model to export, or an archive to export one from")
+                (@arg ENTRY: "Model in the archive to export, by name (folder/name if it's in a folder)")
+                (@arg output: -o --output +takes_value "File to save to (default FILE.obj, or ENTRY.obj in the current directory)")
+                (@arg format: -f --format +takes_value possible_values(&["obj", "gltf"])
+                    "Format to save, instead of going by the output file's extension")
+                (@arg wireframe: -w --wireframe +takes_value "Also save a wireframe of the model from a few angles to this image")
+            )
+            (@subcommand import =>
+                (about: "Turn an OBJ or glTF file back into a model, putting back what its sidecar from export says")
+                (@setting ArgRequiredElseHelp)
+                (@arg FILE: +required {file_still_really_exists} "OBJ or glTF file to import")
+                (@arg output: -o --output +takes_value "File to save to (default FILE.dd_tex1)")
+            )
+            (@subcommand render =>
+                (about: "Draw where a guess at the layout says the vertices are, from a few angles")
+                (@setting ArgRequiredElseHelp)
+                (@arg FILE: +required {file_still_really_exists} "Model to draw, or an archive to draw one from")
+                (@arg ENTRY: "Model in the archive to draw, by name (folder/name if it's in a folder)")
+                (@arg output: -o --output +takes_value "Image to save to (default FILE.png, or ENTRY.png in the current directory)")
+                (@arg start: --start +takes_value "Byte the first vertex starts at (default 10, just after the header)")
+                (@arg stride: --stride +takes_value "Bytes from one vertex to the next (default 32 if the header's counts fit, otherwise a guess)")
+                (@arg offset: --offset +takes_value "Byte the position starts at within each vertex (default 0)")
+                (@arg vertexformat: --("vertex-format") +takes_value possible_values(&["f32", "f16", "i16", "u16"])
+                    "What each of the position's three numbers is stored as (default f32)")
+                (@arg count: --count +takes_value "How many vertices to read (default the header's count if it fits, otherwise all of them)")
+                (@arg points: --points "Only draw the vertices, not the triangles from the indices after them")
+                (@arg guess: --guess
+                    "Try every stride and offset (just offsets, with --stride) and draw the most model-like\nEach is saved as OUTPUT_N_sSTRIDE_oOFFSET.png\nUVs and normals can look like models too, so check the pictures")
+                (@arg top: --top +takes_value "With --guess: how many layouts to draw, 0 to only list them (default 4)")
+                (@arg size: -s --size +takes_value "Size of each view, in pixels (default 256)")
+            )
         )
         (@subcommand pack =>
             (about: "Pack a directory of files into a dd-format archive")
             (@setting ArgRequiredElseHelp)
-            (@arg ARCHIVE: +required "Archive to output to")
+            (@arg ARCHIVE: +required "Archive to output to (- for stdout)")
             (@arg DIR: +required "Directory to get files from")
             (@arg zerotime: -z --nomodtimes "Don't archive file modification times (put in zeros instead)")
+            (@arg types: -t --type +takes_value +multiple number_of_values(1)
+                "Pack a file as a specific type, as PATH=TYPE\nPATH is relative to DIR, TYPE is an extension (dd_tex2) or number (0x02)\nA file's sidecar can also set this, with type = TYPE")
+            (@arg matchoriginal: --("match-original")
+                "Resize images to the size of the textures they were unpacked from, going by their sidecars, and give them as many mipmap levels")
+            (@arg resize: --resize +takes_value possible_values(&["fit", "fill", "stretch"]) requires[matchoriginal]
+                "With --match-original: fit inside the texture, fill it and crop, or stretch to it (default fit)")
         )
     ).get_matches();
 
@@ -93,6 +203,10 @@ fn run() -> Result<()> {
         ("unpack", Some(matches)) => commands::unpack::execute(matches)?,
         ("imgconv", Some(matches)) => commands::imgconv::execute(matches)?,
         ("pack", Some(matches)) => commands::pack::execute(matches)?,
+        ("imgdiff", Some(matches)) => commands::imgdiff::execute(matches)?,
+        ("sheet", Some(matches)) => commands::sheet::execute(matches)?,
+        ("view", Some(matches)) => commands::view::execute(matches)?,
+        ("tex1", Some(matches)) => commands::tex1::execute(matches)?,
         (_, _) => {}
     }
     Ok(())
@@ -20,31 +20,38 @@ named!(pub mainheader<DDMainHeader>,
     )
 );
 
-named!(subheader<DDSubFileHeader>,
+named!(pub subheader_ref<DDSubFileRef>,
     do_parse!(
         file_type: le_u16 >>
         filename: take_until_and_consume_s!("\0") >>
         offset: le_u32 >>
         size: le_u32 >>
         timestamp: le_u32 >>
-        (DDSubFileHeader {
+        (DDSubFileRef {
             file_type: DDFiletype::new(file_type),
-            filename: String::from_utf8_lossy(filename).into_owned(),
-            offset: offset,
-            size: size,
-            timestamp: timestamp
+            filename,
+            offset,
+            size,
+            timestamp
         })
     )
 );
 
-named!(pub header_section_bound<(DDMainHeader, Vec<DDSubFileHeader>)>,
+// Same as `header_section_bound`, but borrows the filenames from the input instead of copying them.
+named!(pub header_section_ref<(DDMainHeader, Vec<DDSubFileRef>)>,
     do_parse!(
         main: mainheader >>
-        files: flat_map!(take!(main.header_length), many_till!(call!(subheader), tag!("\0\0"))) >>
+        files: flat_map!(take!(main.header_length), many_till!(call!(subheader_ref), tag!("\0\0"))) >>
         (main, files.0)
     )
 );
 
+named!(pub header_section_bound<(DDMainHeader, Vec<DDSubFileHeader>)>,
+    map!(header_section_ref, |(main, files): (DDMainHeader, Vec<DDSubFileRef>)| {
+        (main, files.iter().map(DDSubFileRef::to_header).collect())
+    })
+);
+
 named!(pub glsl_file_header<(String, u32, u32)>,
     do_parse!(
         name_len: le_u32 >>
@@ -69,14 +76,15 @@ pub fn read_header<R: Read>(reader: &mut R) -> io::Result<Result<(DDMainHeader,
     let mut header: Vec<u8> = vec![0; 12];
     reader.read_exact(&mut header[..12])?;
     match header_section_bound(header.as_ref()) {
-        Incomplete(Size(size)) => {
-            header.append(&mut vec![0; size]);
-            reader.read_exact(&mut header[12..size+12])?;
+        // `size` is the total length of the header section, including the 12 bytes we already have
+        Incomplete(Size(size)) if size > 12 => {
+            header.resize(size, 0);
+            reader.read_exact(&mut header[12..])?;
             Ok(header_section_bound(header.as_ref()).to_full_result())
         },
         _ => {
             use nom::Needed::Unknown;
-            return Ok(Err(IError::Incomplete(Unknown)));
+            Ok(Err(IError::Incomplete(Unknown)))
         }
     }
 }
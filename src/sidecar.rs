@@ -0,0 +1,96 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use errors::*;
+
+/// Extension added to the end of a file's name to get its sidecar.
+pub const SIDECAR_EXTENSION: &str = "ddmeta";
+
+/// Extra information about a file that doesn't fit in the file itself,
+/// like the archive type to pack it as.
+///
+/// Sidecars sit next to the file they describe, with `.ddmeta` tacked onto the end of its name
+/// (`boid.png` gets `boid.png.ddmeta`). The format is one `key = value` per line.
+/// Blank lines and lines starting with `#` are ignored.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Sidecar {
+    /// Keys and values, in the order they were read or set
+    values: Vec<(String, String)>
+}
+
+impl Sidecar {
+    pub fn new() -> Self {
+        Sidecar::default()
+    }
+
+    /// Where the sidecar for `file` lives.
+    pub fn path_for(file: &Path) -> PathBuf {
+        let mut name = file.file_name().unwrap_or_default().to_os_string();
+        name.push(".");
+        name.push(SIDECAR_EXTENSION);
+        file.with_file_name(name)
+    }
+
+    /// Whether `file` is itself a sidecar.
+    pub fn is_sidecar(file: &Path) -> bool {
+        file.extension().is_some_and(|ext| ext == SIDECAR_EXTENSION)
+    }
+
+    /// Loads the sidecar for `file`, if it has one.
+    pub fn load_for(file: &Path) -> Result<Option<Sidecar>> {
+        let path = Sidecar::path_for(file);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let mut text = String::new();
+        File::open(&path).and_then(|mut f| f.read_to_string(&mut text))
+            .chain_err(|| format!("Failed to read sidecar {}", path.display()))?;
+        Sidecar::parse(&text)
+            .chain_err(|| format!("Failed to parse sidecar {}", path.display()))
+            .map(Some)
+    }
+
+    pub fn parse(text: &str) -> Result<Sidecar> {
+        let mut sidecar = Sidecar::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.find('=') {
+                Some(pos) => sidecar.set(line[..pos].trim(), line[pos+1..].trim()),
+                None => return Err(format!("Line {} isn't of the form key = value", i + 1).into())
+            }
+        }
+        Ok(sidecar)
+    }
+
+    /// Saves this as the sidecar for `file`.
+    pub fn save_for(&self, file: &Path) -> Result<()> {
+        let path = Sidecar::path_for(file);
+        let mut f = File::create(&path)
+            .chain_err(|| format!("Failed to create sidecar {}", path.display()))?;
+        for (key, value) in self.values.iter() {
+            writeln!(f, "{} = {}", key, value)
+                .chain_err(|| format!("Failed to write sidecar {}", path.display()))?;
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+
+    pub fn set<K: Into<String>, V: Into<String>>(&mut self, key: K, value: V) {
+        let (key, value) = (key.into(), value.into());
+        match self.values.iter_mut().find(|(k, _)| *k == key) {
+            Some(existing) => existing.1 = value,
+            None => self.values.push((key, value))
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
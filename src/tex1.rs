@@ -0,0 +1,429 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use nom::{IResult, le_u16, le_u32};
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use encoding;
+use errors::*;
+
+/// Length of the header at the start of every tex1 file, as far as anyone can tell.
+pub const TEX1_HEADER_LENGTH: usize = 10;
+/// How big each vertex would be, if the header's counts are what they seem to be.
+pub const TEX1_VERTEX_SIZE: usize = 32;
+
+// tex1 has no magic number. What's known is two u32s and a u16, and the two u32s line up
+// with the file's length as an index count and a vertex count, so that's what they're called.
+named!(pub tex1_header<Tex1Header>,
+    do_parse!(
+        index_count: le_u32 >>
+        vertex_count: le_u32 >>
+        unknown: le_u16 >>
+        (Tex1Header { index_count, vertex_count, unknown })
+    )
+);
+
+/// The start of a tex1 file. The names are the best guess going, not gospel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tex1Header {
+    /// Probably how many u32 indices come after the vertices
+    pub index_count: u32,
+    /// Probably how many `TEX1_VERTEX_SIZE` byte vertices come after the header
+    pub vertex_count: u32,
+    /// Nobody knows
+    pub unknown: u16
+}
+
+impl Tex1Header {
+    /// How long the file would be if the counts are right, not counting anything after the indices.
+    pub fn expected_length(&self) -> u64 {
+        TEX1_HEADER_LENGTH as u64
+            + self.vertex_count as u64 * TEX1_VERTEX_SIZE as u64
+            + self.index_count as u64 * 4
+    }
+
+    /// Whether a file `length` bytes long with this header could be tex1.
+    pub fn fits(&self, length: u64) -> bool {
+        self.index_count > 0 && self.vertex_count > 0 && self.expected_length() <= length
+    }
+}
+
+/// Where each part of a vertex sits within it, in bytes. Every part is made of f32s.
+///
+/// Nobody knows for sure what's in a tex1 vertex, so this is kept separate from the model
+/// to make trying out other layouts easy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VertexLayout {
+    pub stride: usize,
+    pub position: usize,
+    pub uv: Option<usize>,
+    pub normal: Option<usize>
+}
+
+impl VertexLayout {
+    /// The best guess: position, then texture coordinates, then the normal.
+    pub const GUESS: VertexLayout = VertexLayout { stride: TEX1_VERTEX_SIZE, position: 0, uv: Some(12), normal: Some(20) };
+
+    /// Reads a vertex out of the start of `record`, which has to be at least `stride` bytes.
+    pub fn read_vertex(&self, record: &[u8]) -> Vertex {
+        let float = |offset: usize| f32::from_le_bytes([record[offset], record[offset + 1], record[offset + 2], record[offset + 3]]);
+        Vertex {
+            position: [float(self.position), float(self.position + 4), float(self.position + 8)],
+            uv: self.uv.map(|o| [float(o), float(o + 4)]).unwrap_or_default(),
+            normal: self.normal.map(|o| [float(o), float(o + 4), float(o + 8)]).unwrap_or_default()
+        }
+    }
+
+    /// Writes `vertex` into the start of `record`, the other way around from `read_vertex`.
+    /// Bytes that aren't part of anything are left alone.
+    pub fn write_vertex(&self, vertex: &Vertex, record: &mut [u8]) {
+        let mut put = |offset: usize, values: &[f32]| {
+            for (i, value) in values.iter().enumerate() {
+                record[offset + i * 4..offset + i * 4 + 4].copy_from_slice(&value.to_le_bytes());
+            }
+        };
+        put(self.position, &vertex.position);
+        if let Some(offset) = self.uv {
+            put(offset, &vertex.uv);
+        }
+        if let Some(offset) = self.normal {
+            put(offset, &vertex.normal);
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Vertex {
+    pub position: [f32; 3],
+    pub uv: [f32; 2],
+    pub normal: [f32; 3]
+}
+
+/// A tex1 file read as a triangle mesh, going by `VertexLayout::GUESS`.
+pub struct Tex1Model {
+    /// The header's unknown u16, kept so it can be written back out
+    pub unknown: u16,
+    pub vertices: Vec<Vertex>,
+    /// Three to a triangle
+    pub indices: Vec<u32>,
+    /// Anything after the indices
+    pub trailing: Vec<u8>
+}
+
+impl Tex1Model {
+    pub fn read(data: &[u8]) -> Result<Self> {
+        let header = match tex1_header(data) {
+            IResult::Done(_, header) => header,
+            _ => return Err("tex1 header is truncated".into())
+        };
+        if !header.fits(data.len() as u64) {
+            return Err(format!("tex1 says it has {} indices and {} vertices, which takes {} bytes, but there's only {}",
+                               header.index_count, header.vertex_count, header.expected_length(), data.len()).into());
+        }
+        if !header.index_count.is_multiple_of(3) {
+            return Err(format!("tex1 has {} indices, which isn't a whole number of triangles", header.index_count).into());
+        }
+        let vertex_end = TEX1_HEADER_LENGTH + header.vertex_count as usize * TEX1_VERTEX_SIZE;
+        let index_end = vertex_end + header.index_count as usize * 4;
+        let vertices = data[TEX1_HEADER_LENGTH..vertex_end].chunks_exact(TEX1_VERTEX_SIZE)
+            .map(|record| VertexLayout::GUESS.read_vertex(record))
+            .collect();
+        let indices: Vec<u32> = words(&data[vertex_end..index_end]).collect();
+        if let Some(bad) = indices.iter().find(|&&i| i >= header.vertex_count) {
+            return Err(format!("tex1 has an index of {}, but only {} vertices", bad, header.vertex_count).into());
+        }
+        Ok(Tex1Model { unknown: header.unknown, vertices, indices, trailing: data[index_end..].to_vec() })
+    }
+
+    pub fn save(&self, dst: &mut dyn Write) -> io::Result<()> {
+        dst.write_u32::<LittleEndian>(self.indices.len() as u32)?;
+        dst.write_u32::<LittleEndian>(self.vertices.len() as u32)?;
+        dst.write_u16::<LittleEndian>(self.unknown)?;
+        let mut record = [0u8; TEX1_VERTEX_SIZE];
+        for vertex in self.vertices.iter() {
+            VertexLayout::GUESS.write_vertex(vertex, &mut record);
+            dst.write_all(&record)?;
+        }
+        for &index in self.indices.iter() {
+            dst.write_u32::<LittleEndian>(index)?;
+        }
+        dst.write_all(&self.trailing)
+    }
+
+    /// A 64-bit FNV-1a hash of the model exactly as `save` would write it.
+    pub fn checksum(&self) -> u64 {
+        let mut data = vec![];
+        self.save(&mut data).unwrap();
+        encoding::checksum(&data)
+    }
+
+    pub fn triangles(&self) -> impl Iterator<Item = [u32; 3]> + '_ {
+        self.indices.chunks_exact(3).map(|t| [t[0], t[1], t[2]])
+    }
+}
+
+/// How each of the three numbers in a position might be stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComponentFormat {
+    F32,
+    F16,
+    /// Signed 16-bit, scaled to -1 to 1
+    I16,
+    /// Unsigned 16-bit, scaled to 0 to 1
+    U16
+}
+
+impl ComponentFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "f32" => Some(ComponentFormat::F32),
+            "f16" => Some(ComponentFormat::F16),
+            "i16" => Some(ComponentFormat::I16),
+            "u16" => Some(ComponentFormat::U16),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ComponentFormat::F32 => "f32",
+            ComponentFormat::F16 => "f16",
+            ComponentFormat::I16 => "i16",
+            ComponentFormat::U16 => "u16"
+        }
+    }
+
+    /// Bytes per component.
+    pub fn size(&self) -> usize {
+        if *self == ComponentFormat::F32 { 4 } else { 2 }
+    }
+
+    fn read(&self, bytes: &[u8]) -> f32 {
+        match *self {
+            ComponentFormat::F32 => f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            ComponentFormat::F16 => f16_to_f32(u16::from_le_bytes([bytes[0], bytes[1]])),
+            ComponentFormat::I16 => i16::from_le_bytes([bytes[0], bytes[1]]) as f32 / 32767.0,
+            ComponentFormat::U16 => u16::from_le_bytes([bytes[0], bytes[1]]) as f32 / 65535.0
+        }
+    }
+}
+
+fn f16_to_f32(half: u16) -> f32 {
+    let sign = if half & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = ((half >> 10) & 0x1f) as i32;
+    let mantissa = (half & 0x3ff) as f32;
+    sign * match exponent {
+        0 => mantissa * 2f32.powi(-24),
+        31 => if mantissa == 0.0 { f32::INFINITY } else { f32::NAN },
+        _ => (1.0 + mantissa / 1024.0) * 2f32.powi(exponent - 15)
+    }
+}
+
+/// A guess at where positions are in a run of vertices.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PositionLayout {
+    /// Where the first vertex starts, from the start of the file
+    pub start: usize,
+    pub stride: usize,
+    /// Where the position is within each vertex
+    pub offset: usize,
+    pub format: ComponentFormat
+}
+
+impl PositionLayout {
+    /// Reads up to `count` positions, as many as fit in `data`.
+    pub fn read_positions(&self, data: &[u8], count: usize) -> Vec<[f32; 3]> {
+        let size = self.format.size();
+        let end = self.offset + size * 3;
+        if end > self.stride || self.start >= data.len() {
+            return vec![];
+        }
+        data[self.start..].chunks(self.stride)
+            .take(count)
+            .take_while(|record| record.len() >= end)
+            .map(|record| {
+                let c = |i: usize| self.format.read(&record[self.offset + i * size..]);
+                [c(0), c(1), c(2)]
+            })
+            .collect()
+    }
+}
+
+/// How much a set of positions looks like an actual model, from 0 to 1.
+///
+/// Everything has to be a sensible number, and the edges of `triangles` (or the gaps between
+/// one position and the next, without them) have to be short next to the size of the whole
+/// thing, since real models are made of lots of small pieces and garbage is all over the place.
+/// Triangles also have to cover some area, which rules out normals, where every triangle on a
+/// flat face has the same three.
+pub fn score_positions(positions: &[[f32; 3]], triangles: &[[u32; 3]]) -> f64 {
+    let usable = |p: &[f32; 3]| p.iter().all(|c| c.is_finite() && (c.abs() < 1e5) && (*c == 0.0 || c.abs() > 1e-5));
+    if positions.len() < 3 {
+        return 0.0;
+    }
+    let sensible = positions.iter().filter(|p| usable(p)).count() as f64 / positions.len() as f64;
+    let (mut min, mut max) = ([f32::INFINITY; 3], [f32::NEG_INFINITY; 3]);
+    for p in positions.iter().filter(|p| usable(p)) {
+        for c in 0..3 {
+            min[c] = min[c].min(p[c]);
+            max[c] = max[c].max(p[c]);
+        }
+    }
+    let distance = |a: &[f32; 3], b: &[f32; 3]| {
+        ((0..3).map(|c| ((a[c] - b[c]) as f64).powi(2)).sum::<f64>()).sqrt()
+    };
+    let diagonal = distance(&min, &max);
+    if !diagonal.is_finite() || diagonal == 0.0 {
+        return 0.0;
+    }
+    let mut edges: Vec<(usize, usize)> = triangles.iter()
+        .flat_map(|t| vec![(t[0], t[1]), (t[1], t[2]), (t[2], t[0])])
+        .map(|(a, b)| (a as usize, b as usize))
+        .filter(|&(a, b)| a != b && a < positions.len() && b < positions.len())
+        .collect();
+    if edges.is_empty() {
+        edges = (1..positions.len()).map(|i| (i - 1, i)).collect();
+    }
+    let lengths: Vec<f64> = edges.iter()
+        .filter(|&&(a, b)| usable(&positions[a]) && usable(&positions[b]))
+        .map(|&(a, b)| distance(&positions[a], &positions[b]) / diagonal)
+        .collect();
+    if lengths.is_empty() {
+        return 0.0;
+    }
+    let mean_length = lengths.iter().sum::<f64>() / lengths.len() as f64;
+    let flat = triangles.iter()
+        .filter(|t| t.iter().all(|&i| (i as usize) < positions.len()))
+        .map(|t| (positions[t[0] as usize], positions[t[1] as usize], positions[t[2] as usize]))
+        .filter(|(a, b, c)| {
+            let (u, v) = ([b[0] - a[0], b[1] - a[1], b[2] - a[2]], [c[0] - a[0], c[1] - a[1], c[2] - a[2]]);
+            let cross = [u[1] * v[2] - u[2] * v[1], u[2] * v[0] - u[0] * v[2], u[0] * v[1] - u[1] * v[0]];
+            let area = distance(&cross, &[0.0; 3]);
+            !area.is_finite() || area <= diagonal * diagonal * 1e-6
+        })
+        .count();
+    let with_area = if triangles.is_empty() { 1.0 } else { 1.0 - flat as f64 / triangles.len() as f64 };
+    sensible * with_area * (1.0 - mean_length.min(1.0))
+}
+
+/// How likely it is that records are `stride` bytes apart.
+pub struct StrideGuess {
+    pub stride: usize,
+    /// Fraction of 4-byte words that share a sign and exponent with the word `stride` bytes on
+    pub score: f64
+}
+
+/// What the 4-byte words at one offset into each record look like.
+pub struct ColumnStats {
+    /// Where the column starts in each record
+    pub offset: usize,
+    /// Fraction of the column that makes sense as an f32
+    pub floats: f64,
+    /// Fraction of the column that's a u32 under 65536
+    pub small_ints: f64,
+    /// Smallest, biggest and average of the plausible f32s, if there are any
+    pub float_range: Option<(f32, f32, f32)>
+}
+
+/// How much repetition there is among `stride` byte records.
+pub struct RepeatStats {
+    pub records: usize,
+    pub distinct: usize,
+    /// Index of the record that shows up the most, and how many times it does
+    pub most_common: Option<(usize, usize)>
+}
+
+/// Whether the indices all point at vertices that exist.
+pub struct IndexStats {
+    pub count: usize,
+    pub max: u32,
+    /// How many are past the end of the vertices
+    pub out_of_range: usize
+}
+
+fn words(data: &[u8]) -> impl Iterator<Item = u32> + '_ {
+    data.chunks_exact(4).map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+}
+
+/// Whether `word` looks like a float someone would actually store: zero, or something
+/// that isn't absurdly big or small.
+pub fn plausible_float(word: u32) -> bool {
+    let value = f32::from_bits(word).abs();
+    value == 0.0 || (value.is_finite() && (1e-5..=1e5).contains(&value))
+}
+
+/// Scores every stride from 4 to `max_stride` bytes by how often each word looks like the one
+/// a stride later, best first. Records of floats repeat their exponents, so the right stride
+/// (and its multiples) stand out. Ties go to the smaller stride.
+pub fn guess_strides(data: &[u8], max_stride: usize) -> Vec<StrideGuess> {
+    let words: Vec<u32> = words(data).collect();
+    let mut guesses: Vec<StrideGuess> = (1..=max_stride / 4)
+        .filter(|&step| step < words.len())
+        .map(|step| {
+            let same = words.iter().zip(&words[step..]).filter(|&(a, b)| a >> 23 == b >> 23).count();
+            StrideGuess { stride: step * 4, score: same as f64 / (words.len() - step) as f64 }
+        })
+        .collect();
+    guesses.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap().then(a.stride.cmp(&b.stride)));
+    guesses
+}
+
+/// The smallest stride that scores about as well as the best one, since multiples of the
+/// right stride score just as well.
+pub fn best_stride(guesses: &[StrideGuess]) -> Option<usize> {
+    let best = guesses.first()?.score;
+    guesses.iter().filter(|g| g.score >= best * 0.98).map(|g| g.stride).min()
+}
+
+pub fn column_stats(data: &[u8], stride: usize) -> Vec<ColumnStats> {
+    let records: Vec<&[u8]> = data.chunks_exact(stride).collect();
+    (0..stride / 4).map(|column| {
+        let column_words: Vec<u32> = records.iter().flat_map(|r| words(&r[column * 4..column * 4 + 4])).collect();
+        let floats: Vec<f32> = column_words.iter().filter(|&&w| plausible_float(w)).map(|&w| f32::from_bits(w)).collect();
+        let small_ints = column_words.iter().filter(|&&w| w < 65536).count();
+        let float_range = if floats.is_empty() {
+            None
+        } else {
+            let min = floats.iter().cloned().fold(f32::INFINITY, f32::min);
+            let max = floats.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+            Some((min, max, floats.iter().sum::<f32>() / floats.len() as f32))
+        };
+        let total = column_words.len().max(1) as f64;
+        ColumnStats {
+            offset: column * 4,
+            floats: floats.len() as f64 / total,
+            small_ints: small_ints as f64 / total,
+            float_range
+        }
+    }).collect()
+}
+
+pub fn repeated_records(data: &[u8], stride: usize) -> RepeatStats {
+    // First index and count for each record
+    let mut seen: HashMap<&[u8], (usize, usize)> = HashMap::new();
+    let mut records = 0;
+    for (i, record) in data.chunks_exact(stride).enumerate() {
+        seen.entry(record).or_insert((i, 0)).1 += 1;
+        records += 1;
+    }
+    RepeatStats {
+        records,
+        distinct: seen.len(),
+        most_common: seen.values().filter(|&&(_, count)| count > 1)
+            .max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(&a.0)))
+            .cloned()
+    }
+}
+
+/// Checks `data` as u32 indices into `vertex_count` vertices.
+pub fn index_stats(data: &[u8], vertex_count: u32) -> IndexStats {
+    let mut stats = IndexStats { count: 0, max: 0, out_of_range: 0 };
+    for index in words(data) {
+        stats.count += 1;
+        stats.max = stats.max.max(index);
+        if index >= vertex_count {
+            stats.out_of_range += 1;
+        }
+    }
+    stats
+}
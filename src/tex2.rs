@@ -2,10 +2,16 @@
 use std::io::{self, Write};
 
 use image;
-use image::GenericImage;
-use nom::{le_u8, le_u32};
+use image::{imageops, GenericImage, GenericImageView, ImageBuffer, Pixel, Rgba, RgbaImage};
+use nom::{IResult, le_u8, le_u32};
 use byteorder::{LittleEndian, WriteBytesExt};
 
+use encoding;
+use errors::*;
+
+/// Length of the header at the start of every tex2 file.
+pub const TEX2_HEADER_LENGTH: usize = 11;
+
 named!(pub tex2_header<(u32, u32, u8)>,
     do_parse!(
         tag!("\x11\x40") >> //.@, the magic number for the format
@@ -16,133 +22,676 @@ named!(pub tex2_header<(u32, u32, u8)>,
     )
 );
 
-named!(pub tex2_pixel<(u8, u8, u8, u8)>,
-    tuple!(
-        le_u8,
-        le_u8,
-        le_u8,
-        le_u8
-    )
-);
-
 named!(pub tex2_image<DDTex2Image>,
     do_parse!(
         header: tex2_header >>
-        pixels: count!(tex2_pixel, calc_offset(header.0, header.1, (header.2 as u32)+1) as usize) >>
+        // `read` turns away headers too big to add up, this just stops them panicking on the way
+        pixels: take!(pixel_bytes(header.1, header.0, header.2.max(1) as u32).unwrap_or(usize::MAX)) >>
         (DDTex2Image {
             mipmap_levels: header.2,
-            mipmap_current: 0,
             height: header.0,
             width: header.1,
-            pixels: pixels
+            pixels: pixels.to_vec(),
+            trailing: vec![]
         })
     )
 );
 
 pub struct DDTex2Image {
+    /// How many images are stored, counting the full-size one.
     pub mipmap_levels: u8,
-    mipmap_current: u8,
     pub height: u32,
     pub width: u32,
-    pub pixels: Vec<(u8, u8, u8, u8)>
+    /// Every level's pixels as RGBA bytes, one level straight after the other.
+    pub pixels: Vec<u8>,
+    /// Anything after the last level's pixels. Nobody knows what it's for, but it's kept so it
+    /// gets saved back out untouched.
+    pub trailing: Vec<u8>
 }
 
 impl DDTex2Image {
     pub fn new(width: u32, height: u32) -> Self {
         DDTex2Image {
-            mipmap_levels: 0,
-            mipmap_current: 0,
-            height: height,
-            width: width,
-            pixels: vec![(0, 0, 0, 0); (height*width) as usize]
+            mipmap_levels: 1,
+            height,
+            width,
+            pixels: vec![0; height as usize * width as usize * 4],
+            trailing: vec![]
         }
     }
 
-    pub fn save(&self, mut dst: &mut Write) -> io::Result<()> {
+    /// Builds a texture out of a list of images, the full-size one first and then each mipmap level.
+    ///
+    /// Each level has to be the size `level_dimensions` says it should be.
+    pub fn from_levels(levels: &[RgbaImage]) -> Result<Self> {
+        let base = levels.first().ok_or("Can't make a texture out of no images")?;
+        let mut tex2image = DDTex2Image::from_image(base)?;
+        if levels.len() > max_mipmap_count(tex2image.width, tex2image.height) as usize {
+            return Err(format!("{}x{} can have at most {} mipmap levels, but got {}",
+                               tex2image.width, tex2image.height,
+                               max_mipmap_count(tex2image.width, tex2image.height), levels.len()).into());
+        }
+        for (n, level) in levels.iter().enumerate().skip(1) {
+            let expected = level_dimensions(tex2image.width, tex2image.height, n as u32);
+            if level.dimensions() != expected {
+                return Err(format!("Mipmap level {} should be {}x{}, but is {}x{}", n,
+                                   expected.0, expected.1, level.width(), level.height()).into());
+            }
+            tex2image.pixels.extend_from_slice(level.as_raw());
+        }
+        tex2image.mipmap_levels = levels.len() as u8;
+        Ok(tex2image)
+    }
+
+    /// Builds a texture out of an ordinary image, without any mipmaps.
+    pub fn from_image<I: GenericImageView<Pixel=Rgba<u8>>>(img: &I) -> Result<Self> {
+        let (width, height) = img.dimensions();
+        if width == 0 || height == 0 {
+            return Err(format!("Can't make a texture out of a {}x{} image", width, height).into());
+        }
+        if (width as u64) * (height as u64) > u64::from(u32::MAX) {
+            return Err(format!("{}x{} is too big for a texture", width, height).into());
+        }
+        let mut tex2image = DDTex2Image::new(width, height);
+        tex2image.level_mut(0)?.copy_from(img, 0, 0)
+            .chain_err(|| "Failed to copy image into texture")?;
+        Ok(tex2image)
+    }
+
+    pub fn save(&self, dst: &mut dyn Write) -> io::Result<()> {
         dst.write_u8(0x11)?;
         dst.write_u8(0x40)?;
         dst.write_u32::<LittleEndian>(self.height)?;
         dst.write_u32::<LittleEndian>(self.width)?;
         dst.write_u8(self.mipmap_levels)?;
-        for pixel in self.pixels.iter() {
-            dst.write_u8(pixel.0)?;
-            dst.write_u8(pixel.1)?;
-            dst.write_u8(pixel.2)?;
-            dst.write_u8(pixel.3)?;
+        dst.write_all(&self.pixels)?;
+        dst.write_all(&self.trailing)
+    }
+
+    /// A 64-bit FNV-1a hash of the texture exactly as `save` would write it,
+    /// for telling whether two textures are byte-for-byte the same.
+    pub fn checksum(&self) -> u64 {
+        let mut data = Vec::with_capacity(TEX2_HEADER_LENGTH + self.pixels.len() + self.trailing.len());
+        self.save(&mut data).unwrap();
+        encoding::checksum(&data)
+    }
+
+    /// Where mipmap level `n` sits in `pixels`, in bytes.
+    fn level_range(&self, n: u8) -> Result<::std::ops::Range<usize>> {
+        if n >= self.mipmap_levels.max(1) {
+            return Err(format!("DDTex2Image {}x{}: no mipmap level {}, there's only {}",
+                               self.width, self.height, n, self.mipmap_levels.max(1)).into());
+        }
+        let start = pixel_bytes(self.width, self.height, n as u32);
+        let end = pixel_bytes(self.width, self.height, n as u32 + 1);
+        let (start, end) = match (start, end) {
+            (Some(start), Some(end)) if end <= self.pixels.len() => (start, end),
+            _ => return Err(format!("DDTex2Image {}x{}: mipmap level {} runs past the end of the pixels",
+                                    self.width, self.height, n).into())
+        };
+        Ok(start..end)
+    }
+
+    /// A view of mipmap level `n`, 0 being the full-size image.
+    pub fn level(&self, n: u8) -> Result<Tex2Level<'_>> {
+        let range = self.level_range(n)?;
+        let (width, height) = level_dimensions(self.width, self.height, n as u32);
+        Ok(Tex2Level { width, height, data: &self.pixels[range] })
+    }
+
+    /// A mutable view of mipmap level `n`, 0 being the full-size image.
+    pub fn level_mut(&mut self, n: u8) -> Result<Tex2LevelMut<'_>> {
+        let range = self.level_range(n)?;
+        let (width, height) = level_dimensions(self.width, self.height, n as u32);
+        Ok(Tex2LevelMut { width, height, data: &mut self.pixels[range] })
+    }
+
+    /// Views of every mipmap level, the full-size one first.
+    pub fn levels(&self) -> Result<Vec<Tex2Level<'_>>> {
+        (0..self.mipmap_levels.max(1)).map(|n| self.level(n)).collect()
+    }
+
+    /// Mutable views of every mipmap level at once, the full-size one first.
+    pub fn levels_mut(&mut self) -> Result<Vec<Tex2LevelMut<'_>>> {
+        // Make sure every level is actually there before splitting anything up
+        self.level_range(self.mipmap_levels.max(1) - 1)?;
+        let (width, height) = (self.width, self.height);
+        let mut rest = &mut self.pixels[..];
+        let mut levels = Vec::with_capacity(self.mipmap_levels.max(1) as usize);
+        for n in 0..self.mipmap_levels.max(1) as u32 {
+            let (w, h) = level_dimensions(width, height, n);
+            let (data, next) = rest.split_at_mut(w as usize * h as usize * 4);
+            levels.push(Tex2LevelMut { width: w, height: h, data });
+            rest = next;
+        }
+        Ok(levels)
+    }
+
+    /// Rebuilds every mipmap level from the full-size image, keeping the same number of levels.
+    pub fn regenerate_mipmaps(&mut self, filter: MipmapFilter) -> Result<()> {
+        let mut last = self.level(0)?.to_image();
+        for n in 1..self.mipmap_levels.max(1) {
+            last = filter.downscale(&last);
+            self.level_mut(n)?.as_raw_mut().copy_from_slice(last.as_raw());
         }
         Ok(())
     }
 
-    pub fn set_mipmap(&mut self, new: u8) {
-        if new != 0 && (!self.height.is_power_of_two() || !self.width.is_power_of_two()) {
-            panic!("DDTex2Image {}x{}: can't do mipmap levels on non-power-of-two!", self.width, self.height);
+    /// Parses a whole tex2 file, making sure it's got every pixel its header says it should.
+    pub fn read(data: &[u8]) -> Result<DDTex2Image> {
+        let (height, width, mipmaps) = match tex2_header(data) {
+            IResult::Done(_, header) => header,
+            IResult::Incomplete(_) => return Err("Texture header is truncated".into()),
+            IResult::Error(_) => return Err("Not a tex2 texture (bad magic number)".into())
+        };
+        if width == 0 || height == 0 {
+            return Err(format!("Texture is {}x{}, which can't hold anything", width, height).into());
+        }
+        if mipmaps > max_mipmap_count(width, height) {
+            return Err(format!("Texture is {}x{}, which can't have {} mipmap levels (at most {})",
+                               width, height, mipmaps, max_mipmap_count(width, height)).into());
+        }
+        let expected = pixel_bytes(width, height, mipmaps.max(1) as u32)
+            .ok_or_else(|| format!("Texture is {}x{}, which is too big to hold", width, height))?;
+        let available = data.len() - TEX2_HEADER_LENGTH;
+        if available < expected {
+            return Err(format!("Texture is truncated: {}x{} with {} mipmap level{} needs {} bytes of pixels, but there's only {}",
+                               width, height, mipmaps, if mipmaps == 1 {""} else {"s"}, expected, available).into());
+        }
+        match tex2_image(data) {
+            IResult::Done(rest, mut tex2image) => {
+                tex2image.trailing = rest.to_vec();
+                Ok(tex2image)
+            },
+            // The length was already checked, so this really shouldn't happen
+            _ => Err("Failed to parse texture".into())
         }
-        self.mipmap_current = new;
+    }
+}
+
+/// One mipmap level of a `DDTex2Image`, borrowed straight out of its pixels.
+#[derive(Clone, Copy)]
+pub struct Tex2Level<'a> {
+    width: u32,
+    height: u32,
+    data: &'a [u8]
+}
+
+impl<'a> Tex2Level<'a> {
+    /// The level's RGBA bytes, row by row.
+    pub fn as_raw(&self) -> &'a [u8] {
+        self.data
     }
 
-    pub fn cur_width(&self) -> u32 {
-        self.width >> self.mipmap_current
+    /// Copies the level out into an ordinary image.
+    pub fn to_image(&self) -> RgbaImage {
+        ImageBuffer::from_raw(self.width, self.height, self.data.to_vec())
+            .expect("Tex2Level is always exactly width*height pixels")
     }
+}
+
+impl<'a> GenericImageView for Tex2Level<'a> {
+    type Pixel = Rgba<u8>;
+    type InnerImageView = Self;
 
-    pub fn cur_height(&self) -> u32 {
-        self.height >> self.mipmap_current
+    fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
     }
 
-    pub fn pos(&self, x: u32, y: u32) -> usize {
-        (calc_offset(self.height, self.width, (self.mipmap_current) as u32)
-            + ((self.cur_width()*y) + x)) as usize
+    fn bounds(&self) -> (u32, u32, u32, u32) {
+        (0, 0, self.width, self.height)
     }
-    pub fn pixel(&self, x: u32, y: u32) -> (u8, u8, u8, u8) {
-        match self.pixels.get(self.pos(x, y)) {
-            Some(p) => *p,
-            None => (0xFF, 0xFF, 0xFF, 0xFF)
+
+    fn get_pixel(&self, x: u32, y: u32) -> Self::Pixel {
+        let pos = pixel_pos(self.width, self.height, x, y);
+        *Rgba::from_slice(&self.data[pos..pos + 4])
+    }
+
+    fn inner(&self) -> &Self::InnerImageView {
+        self
+    }
+}
+
+/// One mipmap level of a `DDTex2Image`, mutably borrowed out of its pixels.
+pub struct Tex2LevelMut<'a> {
+    width: u32,
+    height: u32,
+    data: &'a mut [u8]
+}
+
+impl<'a> Tex2LevelMut<'a> {
+    /// The level's RGBA bytes, row by row.
+    pub fn as_raw(&self) -> &[u8] {
+        self.data
+    }
+
+    pub fn as_raw_mut(&mut self) -> &mut [u8] {
+        self.data
+    }
+
+    /// Copies the level out into an ordinary image.
+    pub fn to_image(&self) -> RgbaImage {
+        ImageBuffer::from_raw(self.width, self.height, self.data.to_vec())
+            .expect("Tex2LevelMut is always exactly width*height pixels")
+    }
+}
+
+impl<'a> GenericImageView for Tex2LevelMut<'a> {
+    type Pixel = Rgba<u8>;
+    type InnerImageView = Self;
+
+    fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    fn bounds(&self) -> (u32, u32, u32, u32) {
+        (0, 0, self.width, self.height)
+    }
+
+    fn get_pixel(&self, x: u32, y: u32) -> Self::Pixel {
+        let pos = pixel_pos(self.width, self.height, x, y);
+        *Rgba::from_slice(&self.data[pos..pos + 4])
+    }
+
+    fn inner(&self) -> &Self::InnerImageView {
+        self
+    }
+}
+
+impl<'a> GenericImage for Tex2LevelMut<'a> {
+    type InnerImage = Self;
+
+    fn get_pixel_mut(&mut self, x: u32, y: u32) -> &mut Self::Pixel {
+        let pos = pixel_pos(self.width, self.height, x, y);
+        Rgba::from_slice_mut(&mut self.data[pos..pos + 4])
+    }
+
+    fn put_pixel(&mut self, x: u32, y: u32, pixel: Self::Pixel) {
+        *self.get_pixel_mut(x, y) = pixel;
+    }
+
+    fn blend_pixel(&mut self, x: u32, y: u32, pixel: Self::Pixel) {
+        self.get_pixel_mut(x, y).blend(&pixel);
+    }
+
+    fn inner_mut(&mut self) -> &mut Self::InnerImage {
+        self
+    }
+}
+
+/// Where pixel `x`,`y` of a `width`x`height` level starts, in bytes.
+fn pixel_pos(width: u32, height: u32, x: u32, y: u32) -> usize {
+    if x >= width || y >= height {
+        panic!("DDTex2Image: pixel {},{} is out of bounds for {}x{}", x, y, width, height);
+    }
+    (y as usize * width as usize + x as usize) * 4
+}
+
+/// Filters for shrinking each mipmap level out of the one above it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MipmapFilter {
+    /// Average each 2x2 block of pixels. Cheap and exact for halving.
+    Box,
+    /// Linear filter
+    Triangle,
+    /// Lanczos with window 3, sharpest of the bunch
+    Lanczos
+}
+
+impl MipmapFilter {
+    pub const ALL: [MipmapFilter; 3] = [MipmapFilter::Box, MipmapFilter::Triangle, MipmapFilter::Lanczos];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "box" => Some(MipmapFilter::Box),
+            "triangle" => Some(MipmapFilter::Triangle),
+            "lanczos" => Some(MipmapFilter::Lanczos),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            MipmapFilter::Box => "box",
+            MipmapFilter::Triangle => "triangle",
+            MipmapFilter::Lanczos => "lanczos"
+        }
+    }
+
+    /// Shrinks `img` down to half its size.
+    pub fn downscale(&self, img: &RgbaImage) -> RgbaImage {
+        let (width, height) = ((img.width() / 2).max(1), (img.height() / 2).max(1));
+        match *self {
+            MipmapFilter::Box => ImageBuffer::from_fn(width, height, |x, y| {
+                // Clamp so 1-pixel-wide images just average down the other way
+                let xs = [(x*2).min(img.width()-1), (x*2+1).min(img.width()-1)];
+                let ys = [(y*2).min(img.height()-1), (y*2+1).min(img.height()-1)];
+                let mut sum = [0u32; 4];
+                for &sx in xs.iter() {
+                    for &sy in ys.iter() {
+                        let p = img.get_pixel(sx, sy);
+                        for c in 0..4 { sum[c] += p[c] as u32; }
+                    }
+                }
+                // +2 to round to nearest instead of down
+                image::Rgba([((sum[0]+2)/4) as u8, ((sum[1]+2)/4) as u8,
+                             ((sum[2]+2)/4) as u8, ((sum[3]+2)/4) as u8])
+            }),
+            MipmapFilter::Triangle => imageops::resize(img, width, height, imageops::FilterType::Triangle),
+            MipmapFilter::Lanczos => imageops::resize(img, width, height, imageops::FilterType::Lanczos3)
         }
     }
 }
 
-#[allow(unused_variables)]
-impl GenericImage for DDTex2Image {
-    type Pixel = image::Rgba<u8>;
+/// Orders the four channels of a pixel could be stored in.
+///
+/// Textures are assumed to be RGBA, but nobody's completely sure, so the others are there to try out.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelOrder {
+    #[default]
+    Rgba,
+    Bgra,
+    Argb
+}
+
+impl ChannelOrder {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "rgba" => Some(ChannelOrder::Rgba),
+            "bgra" => Some(ChannelOrder::Bgra),
+            "argb" => Some(ChannelOrder::Argb),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ChannelOrder::Rgba => "rgba",
+            ChannelOrder::Bgra => "bgra",
+            ChannelOrder::Argb => "argb"
+        }
+    }
+
+    /// Where red, green, blue and alpha are in a stored pixel.
+    fn positions(&self) -> [usize; 4] {
+        match *self {
+            ChannelOrder::Rgba => [0, 1, 2, 3],
+            ChannelOrder::Bgra => [2, 1, 0, 3],
+            ChannelOrder::Argb => [1, 2, 3, 0]
+        }
+    }
+
+    /// Rearranges pixels stored in this order into RGBA.
+    pub fn unswizzle(&self, data: &mut [u8]) {
+        let positions = self.positions();
+        for pixel in data.chunks_exact_mut(4) {
+            let stored = [pixel[0], pixel[1], pixel[2], pixel[3]];
+            for (c, &pos) in positions.iter().enumerate() {
+                pixel[c] = stored[pos];
+            }
+        }
+    }
+
+    /// Rearranges RGBA pixels into this order.
+    pub fn swizzle(&self, data: &mut [u8]) {
+        let positions = self.positions();
+        for pixel in data.chunks_exact_mut(4) {
+            let rgba = [pixel[0], pixel[1], pixel[2], pixel[3]];
+            for (c, &pos) in positions.iter().enumerate() {
+                pixel[pos] = rgba[c];
+            }
+        }
+    }
+}
+
+/// Multiplies the colour of each RGBA pixel by its alpha.
+pub fn premultiply(data: &mut [u8]) {
+    for pixel in data.chunks_exact_mut(4) {
+        let alpha = pixel[3] as u32;
+        for c in pixel[..3].iter_mut() {
+            *c = ((*c as u32 * alpha + 127) / 255) as u8;
+        }
+    }
+}
+
+/// Undoes `premultiply`, as far as that's possible. Fully transparent pixels come out black.
+pub fn unpremultiply(data: &mut [u8]) {
+    for pixel in data.chunks_exact_mut(4) {
+        let alpha = pixel[3] as u32;
+        for c in pixel[..3].iter_mut() {
+            *c = (*c as u32 * 255 + alpha / 2).checked_div(alpha).map_or(0, |c| c.min(255) as u8);
+        }
+    }
+}
+
+/// How many levels a full mipmap chain for a `width`x`height` texture has, counting the full-size image.
+///
+/// Each level is half the size of the last, until it's down to 1x1. That's also the most a
+/// texture can have, since past that every level would just be another 1x1.
+pub fn max_mipmap_count(width: u32, height: u32) -> u8 {
+    (32 - width.max(height).max(1).leading_zeros()) as u8
+}
+
+/// The full-size image, so a texture can be handed straight to anything in `image`.
+/// Use `level` or `level_mut` to get at the mipmaps.
+impl GenericImageView for DDTex2Image {
+    type Pixel = Rgba<u8>;
+    type InnerImageView = Self;
 
     fn dimensions(&self) -> (u32, u32) {
-        (self.cur_width(), self.cur_height())
+        (self.width, self.height)
     }
 
     fn bounds(&self) -> (u32, u32, u32, u32) {
-        (0, 0, self.cur_width(), self.cur_height())
+        (0, 0, self.width, self.height)
     }
 
     fn get_pixel(&self, x: u32, y: u32) -> Self::Pixel {
-        let p = self.pixel(x, y);
-        image::Rgba([p.0, p.1, p.2, p.3])
+        let pos = pixel_pos(self.width, self.height, x, y);
+        *Rgba::from_slice(&self.pixels[pos..pos + 4])
     }
 
+    fn inner(&self) -> &Self::InnerImageView {
+        self
+    }
+}
+
+/// Drawing onto a texture only touches the full-size image, so call `regenerate_mipmaps`
+/// afterwards to get the smaller levels to match before saving.
+impl GenericImage for DDTex2Image {
+    type InnerImage = Self;
+
     fn get_pixel_mut(&mut self, x: u32, y: u32) -> &mut Self::Pixel {
-        unimplemented!()
+        let pos = pixel_pos(self.width, self.height, x, y);
+        Rgba::from_slice_mut(&mut self.pixels[pos..pos + 4])
     }
 
     fn put_pixel(&mut self, x: u32, y: u32, pixel: Self::Pixel) {
-        let pos = self.pos(x, y);
-        self.pixels[pos] = (pixel[0], pixel[1], pixel[2], pixel[3]);
+        *self.get_pixel_mut(x, y) = pixel;
     }
 
     fn blend_pixel(&mut self, x: u32, y: u32, pixel: Self::Pixel) {
-        unimplemented!()
+        self.get_pixel_mut(x, y).blend(&pixel);
+    }
+
+    fn inner_mut(&mut self) -> &mut Self::InnerImage {
+        self
     }
 }
 
 
-//12:36:48 AM <ubsan> zekesonxx: lemme think about it
-//12:36:51 AM <ubsan> I have an idea
-//12:37:24 AM <zekesonxx> ubsan: shoot
-//12:37:42 AM <ubsan> zekesonxx: alright, so the function definition would be something like
-//12:37:57 AM <ubsan> fn foo(x: usize, y: usize, n: u32) -> usize {
-//12:39:51 AM <ubsan>   let (x, y) = (x.trailing_zeroes(), y.trailing_zeroes()); (0..n).fold(1, |acc, n| acc += 1 << (x - n) * 1 << (y - n))
-//12:39:55 AM <ubsan> something like this ^?
-//12:52:55 AM <zekesonxx> ubsan: little bit of reworking needed but looks good. Thanks for the help.
-//12:53:01 AM <ubsan> zekesonxx: yep!
-//12:53:10 AM * ubsan has done some assembly in the past :P
-fn calc_offset(height: u32, width: u32, cur_mip: u32) -> u32 {
-    let (x, y) = (height.trailing_zeros(), width.trailing_zeros());
-    (0..cur_mip).fold(0u32, |acc, n| acc + (1 << (x - n) * 1 << (y - n)))
-}
\ No newline at end of file
+/// Size of a mipmap level. Each level is half the size of the last, rounding down, but never smaller than 1.
+pub fn level_dimensions(width: u32, height: u32, level: u32) -> (u32, u32) {
+    let shrink = |side: u32| side.checked_shr(level).unwrap_or(0).max(1);
+    (shrink(width), shrink(height))
+}
+
+/// How long a `width`x`height` texture with `levels` mipmap levels is once it's saved,
+/// not counting anything after the pixels. `None` if it's too big to add up.
+pub fn encoded_length(width: u32, height: u32, levels: u32) -> Option<usize> {
+    pixel_bytes(width, height, levels.max(1))?.checked_add(TEX2_HEADER_LENGTH)
+}
+
+/// Where mipmap level `level` starts, in pixels.
+/// This is also how many pixels there are in the first `level` levels.
+/// `None` if that's more than fits in a `usize`.
+fn calc_offset(width: u32, height: u32, level: u32) -> Option<usize> {
+    (0..level).try_fold(0usize, |acc, n| {
+        let (w, h) = level_dimensions(width, height, n);
+        (w as usize).checked_mul(h as usize).and_then(|pixels| acc.checked_add(pixels))
+    })
+}
+
+/// Where mipmap level `level` starts, in bytes.
+fn pixel_bytes(width: u32, height: u32, level: u32) -> Option<usize> {
+    calc_offset(width, height, level)?.checked_mul(4)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use archive::MappedArchive;
+    use types::DDFiletype;
+
+    // The fixtures are made by hand, not pulled out of the game: every pixel is
+    // [level, x, y, 255], so each level's offset can be checked against what's actually there.
+    // They only show the code agrees with itself; `real_textures` checks it against the game.
+    // npot_5x3 also has two trailing bytes.
+    const SQUARE: &[u8] = include_bytes!("../testdata/tex2/square_4x4.dd_tex2");
+    const RECT: &[u8] = include_bytes!("../testdata/tex2/rect_8x2.dd_tex2");
+    const NPOT: &[u8] = include_bytes!("../testdata/tex2/npot_5x3.dd_tex2");
+
+    fn check_golden(data: &[u8], dimensions: &[(u32, u32)], offsets: &[usize]) {
+        let image = DDTex2Image::read(data).unwrap();
+        assert_eq!(image.mipmap_levels as usize, dimensions.len());
+        for (n, (&size, &offset)) in dimensions.iter().zip(offsets).enumerate() {
+            assert_eq!(level_dimensions(image.width, image.height, n as u32), size);
+            assert_eq!(calc_offset(image.width, image.height, n as u32), Some(offset));
+            let level = image.level(n as u8).unwrap();
+            assert_eq!(level.dimensions(), size);
+            let raw = level.as_raw();
+            let (w, h) = size;
+            assert_eq!(&raw[..4], &[n as u8, 0, 0, 255]);
+            assert_eq!(&raw[raw.len() - 4..], &[n as u8, w as u8 - 1, h as u8 - 1, 255]);
+        }
+
+        let mut saved = vec![];
+        image.save(&mut saved).unwrap();
+        assert_eq!(saved, data);
+    }
+
+    #[test]
+    fn square() {
+        check_golden(SQUARE, &[(4, 4), (2, 2), (1, 1)], &[0, 16, 20]);
+    }
+
+    #[test]
+    fn rectangular() {
+        check_golden(RECT, &[(8, 2), (4, 1), (2, 1), (1, 1)], &[0, 16, 20, 22]);
+    }
+
+    #[test]
+    fn non_power_of_two() {
+        check_golden(NPOT, &[(5, 3), (2, 1), (1, 1)], &[0, 15, 17]);
+        assert_eq!(DDTex2Image::read(NPOT).unwrap().trailing, vec![0xde, 0xad]);
+    }
+
+    #[test]
+    fn mismatches_are_errors() {
+        assert!(DDTex2Image::read(&SQUARE[..SQUARE.len() - 1]).is_err());
+        assert!(DDTex2Image::read(b"\x11\x40\xff\xff\xff\xff\xff\xff\xff\xff\x01").is_err());
+        let mut too_many_levels = SQUARE.to_vec();
+        too_many_levels[10] = 4;
+        assert!(DDTex2Image::read(&too_many_levels).is_err());
+    }
+
+    /// Checks the layout against a real res/dd from the game, which can't be kept in the repo:
+    /// `DEVILTOOL_RES_DD=/path/to/res/dd cargo test -- --ignored real_textures`
+    ///
+    /// Every texture has to read, save back byte-for-byte, and for most of them each mipmap level
+    /// has to look like the one above it shrunk down, which it won't if the levels are in the wrong place.
+    #[test]
+    #[ignore]
+    fn real_textures() {
+        let path = env::var_os("DEVILTOOL_RES_DD").expect("DEVILTOOL_RES_DD should be the path to the game's res/dd");
+        let archive = MappedArchive::open(&path).unwrap();
+        let (mut textures, mut with_mipmaps, mut matching) = (0, 0, 0);
+        for entry in archive.entries().into_iter().filter(|e| e.file_type == DDFiletype::Texture2) {
+            let name = entry.filename_lossy();
+            let data = archive.data(&entry);
+            let image = DDTex2Image::read(data).unwrap_or_else(|e| panic!("{}: {}", name, e));
+            let mut saved = vec![];
+            image.save(&mut saved).unwrap();
+            assert!(saved == data, "{} doesn't save back the same", name);
+            textures += 1;
+
+            let levels = image.levels().unwrap_or_else(|e| panic!("{}: {}", name, e));
+            if levels.len() < 2 {
+                continue;
+            }
+            with_mipmaps += 1;
+            let looks_shrunk = levels.windows(2).all(|pair| {
+                let shrunk = MipmapFilter::Box.downscale(&pair[0].to_image());
+                let difference: u64 = shrunk.as_raw().iter().zip(pair[1].as_raw())
+                    .map(|(&a, &b)| (a as i32 - b as i32).unsigned_abs() as u64).sum();
+                difference / (shrunk.as_raw().len() as u64) < 24
+            });
+            if looks_shrunk {
+                matching += 1;
+            } else {
+                println!("{} ({}x{}, {} levels): mipmaps don't look like the levels above them",
+                         name, image.width, image.height, levels.len());
+            }
+        }
+        println!("{} textures, {} with mipmaps, {} of those look right", textures, with_mipmaps, matching);
+        assert!(textures > 0, "no textures in {:?}", path);
+        assert!(matching * 10 >= with_mipmaps * 9, "too many textures' mipmaps don't line up");
+    }
+
+    #[test]
+    fn every_level_at_once() {
+        let mut image = DDTex2Image::read(RECT).unwrap();
+        {
+            let mut levels = image.levels_mut().unwrap();
+            assert_eq!(levels.iter().map(|l| l.dimensions()).collect::<Vec<_>>(), vec![(8, 2), (4, 1), (2, 1), (1, 1)]);
+            // Copy each level's last pixel into the one above it, which needs two levels borrowed at once
+            for n in (1..levels.len()).rev() {
+                let (above, below) = levels.split_at_mut(n);
+                let (w, h) = below[0].dimensions();
+                let pixel = below[0].get_pixel(w - 1, h - 1);
+                above[n - 1].put_pixel(0, 0, pixel);
+            }
+        }
+        assert_eq!(image.get_pixel(0, 0), Rgba([1, 3, 0, 255]));
+        assert_eq!(image.level(1).unwrap().get_pixel(0, 0), Rgba([2, 1, 0, 255]));
+        assert_eq!(image.level(2).unwrap().get_pixel(0, 0), Rgba([3, 0, 0, 255]));
+
+        image.pixels.truncate(image.pixels.len() - 4);
+        assert!(image.levels_mut().is_err());
+    }
+
+    #[test]
+    fn composite_then_regenerate() {
+        let mut image = DDTex2Image::read(SQUARE).unwrap();
+        let logo = ImageBuffer::from_pixel(2, 2, Rgba([200, 100, 50, 128]));
+        imageops::overlay(&mut image, &logo, 2, 0);
+        let mut blended = Rgba([0, 3, 1, 255]);
+        blended.blend(&Rgba([200, 100, 50, 128]));
+        assert_eq!(image.get_pixel(3, 1), blended);
+        assert_eq!(image.get_pixel(1, 1), Rgba([0, 1, 1, 255]));
+        // Only the full-size image has the logo until the mipmaps are rebuilt
+        assert_eq!(image.level(1).unwrap().get_pixel(1, 0), Rgba([1, 1, 0, 255]));
+
+        image.regenerate_mipmaps(MipmapFilter::Box).unwrap();
+        let mut expected = image.level(0).unwrap().to_image();
+        for n in 1..3 {
+            expected = MipmapFilter::Box.downscale(&expected);
+            assert_eq!(image.level(n).unwrap().to_image(), expected);
+        }
+        assert_ne!(image.level(1).unwrap().get_pixel(1, 0), Rgba([1, 1, 0, 255]));
+        let mut saved = vec![];
+        image.save(&mut saved).unwrap();
+        assert_eq!(DDTex2Image::read(&saved).unwrap().pixels, image.pixels);
+    }
+}
@@ -1,5 +1,7 @@
 
 use std::fmt;
+use std::str;
+use std::borrow::Cow;
 
 use std::io::prelude::*;
 use std::io;
@@ -17,6 +19,21 @@ pub struct DDMainHeader {
     pub header_length: u32
 }
 
+impl DDMainHeader {
+    pub fn new(header_length: u32) -> Self {
+        DDMainHeader {
+            magic_number: Vec::from(&b":hx:rg:\x01"[..]),
+            header_length
+        }
+    }
+
+    pub fn write(&self, dst: &mut dyn Write) -> io::Result<()> {
+        dst.write_all(&self.magic_number)?;
+        dst.write_u32::<LittleEndian>(self.header_length)?;
+        Ok(())
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct DDSubFileHeader {
     /// File type
@@ -33,9 +50,9 @@ pub struct DDSubFileHeader {
 }
 
 impl DDSubFileHeader {
-    pub fn write(&self, mut dst: &mut Write) -> io::Result<()> {
+    pub fn write(&self, dst: &mut dyn Write) -> io::Result<()> {
         dst.write_u16::<LittleEndian>(self.file_type.to_u16())?;
-        dst.write(self.filename.as_bytes())?;
+        dst.write_all(self.filename.as_bytes())?;
         dst.write_u8(0)?; // Null term for filename
         dst.write_u32::<LittleEndian>(self.offset)?;
         dst.write_u32::<LittleEndian>(self.size)?;
@@ -44,6 +61,53 @@ impl DDSubFileHeader {
     }
 }
 
+/// A subheader that borrows its filename from the archive it was parsed out of.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct DDSubFileRef<'a> {
+    /// File type
+    pub file_type: DDFiletype,
+    /// Filename, as it appears in the archive (without the null terminator)
+    pub filename: &'a [u8],
+    /// File's position (offset in bytes from the beginning of the file)
+    pub offset: u32,
+    /// Length/size of the file, in bytes
+    pub size: u32,
+    /// Unix timestamp.
+    pub timestamp: u32
+}
+
+impl<'a> DDSubFileRef<'a> {
+    /// The filename, if it's valid UTF-8.
+    pub fn filename_str(&self) -> Option<&'a str> {
+        str::from_utf8(self.filename).ok()
+    }
+
+    /// The filename, with anything that isn't valid UTF-8 replaced.
+    pub fn filename_lossy(&self) -> Cow<'a, str> {
+        String::from_utf8_lossy(self.filename)
+    }
+
+    /// Copies this into an owned `DDSubFileHeader`.
+    pub fn to_header(&self) -> DDSubFileHeader {
+        DDSubFileHeader {
+            file_type: self.file_type,
+            filename: self.filename_lossy().into_owned(),
+            offset: self.offset,
+            size: self.size,
+            timestamp: self.timestamp
+        }
+    }
+}
+/// Writes out a combined GLSL shader, the same layout `parser::glsl_file` reads.
+pub fn write_glsl_file(dst: &mut dyn Write, name: &str, vertex: &str, fragment: &str) -> io::Result<()> {
+    dst.write_u32::<LittleEndian>(name.len() as u32)?;
+    dst.write_u32::<LittleEndian>(vertex.len() as u32)?;
+    dst.write_u32::<LittleEndian>(fragment.len() as u32)?;
+    dst.write_all(name.as_bytes())?;
+    dst.write_all(vertex.as_bytes())?;
+    dst.write_all(fragment.as_bytes())?;
+    Ok(())
+}
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum DDFiletype {
@@ -112,16 +176,36 @@ impl DDFiletype {
             "dd_tex1" => Some(Texture1),
             "dd_tex2" => Some(Texture2),
             "shadercfg" => Some(ShaderText),
-            _ => None
+            "foldermarker" => Some(FolderMarker),
+            _ => {
+                // dd_0xXX, which is what extension() gives types we don't know about
+                strip_prefix_ignore_case(ext, "dd_0x")
+                    .and_then(|hex| u16::from_str_radix(hex, 16).ok())
+                    .map(DDFiletype::new)
+            }
+        }
+    }
+    /// Parses a type given by a person, either as an extension (`dd_tex2`, `dd_0x2`)
+    /// or as the number itself (`0x02`, `2`).
+    pub fn from_name(name: &str) -> Option<Self> {
+        if let Some(t) = DDFiletype::from_extension(name) {
+            return Some(t);
+        }
+        match strip_prefix_ignore_case(name, "0x") {
+            Some(hex) => u16::from_str_radix(hex, 16).ok().map(DDFiletype::new),
+            None => name.parse::<u16>().ok().map(DDFiletype::new)
         }
     }
     pub fn is_unknown(&self) -> bool {
         use self::DDFiletype::*;
-        if let &Unknown(_) = self {
-            true
-        } else {
-            false
-        }
+        matches!(*self, Unknown(_))
+    }
+}
+
+fn strip_prefix_ignore_case<'a>(s: &'a str, prefix: &str) -> Option<&'a str> {
+    match s.get(..prefix.len()) {
+        Some(start) if start.eq_ignore_ascii_case(prefix) => Some(&s[prefix.len()..]),
+        _ => None
     }
 }
 
@@ -0,0 +1,106 @@
+use image::{ImageBuffer, Pixel, Rgba, RgbaImage};
+
+use font::{self, GLYPH_HEIGHT};
+
+/// Space around each view and between a view and its label.
+const PADDING: u32 = 6;
+const BACKGROUND: Rgba<u8> = Rgba([24, 24, 24, 255]);
+const TEXT_COLOUR: Rgba<u8> = Rgba([224, 224, 224, 255]);
+const LINE_COLOUR: Rgba<u8> = Rgba([120, 220, 120, 160]);
+const POINT_COLOUR: Rgba<u8> = Rgba([255, 200, 80, 255]);
+
+/// A direction to look at a model from.
+#[derive(Debug, Clone, Copy)]
+pub struct View {
+    pub name: &'static str,
+    /// Turn around the vertical axis, then tip forward, in degrees
+    pub yaw: f32,
+    pub pitch: f32
+}
+
+/// Front, side, top and an angle that shows a bit of all three.
+pub const VIEWS: [View; 4] = [
+    View { name: "front", yaw: 0.0, pitch: 0.0 },
+    View { name: "side", yaw: 90.0, pitch: 0.0 },
+    View { name: "top", yaw: 0.0, pitch: 90.0 },
+    View { name: "angled", yaw: 45.0, pitch: 30.0 }
+];
+
+impl View {
+    /// Where `point` ends up on screen, before scaling: x to the right, y up.
+    fn project(&self, point: [f32; 3]) -> (f32, f32) {
+        let (yaw, pitch) = (self.yaw.to_radians(), self.pitch.to_radians());
+        let x = point[0] * yaw.cos() - point[2] * yaw.sin();
+        let z = point[0] * yaw.sin() + point[2] * yaw.cos();
+        let y = point[1] * pitch.cos() + z * pitch.sin();
+        (x, y)
+    }
+}
+
+/// Draws `points` from every one of `views`, side by side in a grid two wide, each view
+/// `size` pixels square and labelled underneath. With `triangles`, their edges are drawn
+/// as well as the points themselves.
+///
+/// Each view is scaled to fit on its own, so it's the shape that counts, not the size.
+pub fn render(points: &[[f32; 3]], triangles: &[[u32; 3]], views: &[View], size: u32) -> RgbaImage {
+    let columns = (views.len() as u32).clamp(1, 2);
+    let rows = (views.len() as u32).div_ceil(columns);
+    let (cell_width, cell_height) = (size + PADDING, size + PADDING + GLYPH_HEIGHT + PADDING);
+    let mut image = ImageBuffer::from_pixel(columns * cell_width + PADDING, rows * cell_height + PADDING, BACKGROUND);
+    // Nonsense data is full of NaNs and huge numbers, which are no use to anybody
+    let usable = |p: &[f32; 3]| p.iter().all(|c| c.is_finite() && c.abs() < 1e6);
+
+    for (i, view) in views.iter().enumerate() {
+        let (left, top) = (PADDING + (i as u32 % columns) * cell_width, PADDING + (i as u32 / columns) * cell_height);
+        let projected: Vec<Option<(f32, f32)>> = points.iter()
+            .map(|p| if usable(p) { Some(view.project(*p)) } else { None })
+            .collect();
+        let (mut min, mut max) = ((f32::INFINITY, f32::INFINITY), (f32::NEG_INFINITY, f32::NEG_INFINITY));
+        for &(x, y) in projected.iter().flatten() {
+            min = (min.0.min(x), min.1.min(y));
+            max = (max.0.max(x), max.1.max(y));
+        }
+        if min.0 <= max.0 {
+            // Same scale both ways, centred, with y flipped since images go down
+            let scale = size.saturating_sub(1) as f32 / (max.0 - min.0).max(max.1 - min.1).max(f32::EPSILON);
+            let (centre_x, centre_y) = ((min.0 + max.0) / 2.0, (min.1 + max.1) / 2.0);
+            let to_pixel = |(x, y): (f32, f32)| {
+                (left as f32 + size as f32 / 2.0 + (x - centre_x) * scale,
+                 top as f32 + size as f32 / 2.0 - (y - centre_y) * scale)
+            };
+            for triangle in triangles {
+                let corners: Vec<(f32, f32)> = triangle.iter()
+                    .filter_map(|&v| projected.get(v as usize).cloned().flatten())
+                    .map(to_pixel)
+                    .collect();
+                if corners.len() == 3 {
+                    for edge in 0..3 {
+                        draw_line(&mut image, corners[edge], corners[(edge + 1) % 3], LINE_COLOUR);
+                    }
+                }
+            }
+            for &point in projected.iter().flatten() {
+                let (x, y) = to_pixel(point);
+                blend_pixel(&mut image, x.round() as i64, y.round() as i64, POINT_COLOUR);
+            }
+        }
+        font::draw_text(&mut image, left, top + size + PADDING, &font::fit_text(view.name, size), TEXT_COLOUR);
+    }
+    image
+}
+
+fn blend_pixel(image: &mut RgbaImage, x: i64, y: i64, colour: Rgba<u8>) {
+    if x >= 0 && y >= 0 && (x as u32) < image.width() && (y as u32) < image.height() {
+        image.get_pixel_mut(x as u32, y as u32).blend(&colour);
+    }
+}
+
+/// Steps along the line a pixel at a time along its longer side.
+fn draw_line(image: &mut RgbaImage, from: (f32, f32), to: (f32, f32), colour: Rgba<u8>) {
+    let (dx, dy) = (to.0 - from.0, to.1 - from.1);
+    let steps = dx.abs().max(dy.abs()).ceil().max(1.0) as u32;
+    for step in 0..=steps {
+        let t = step as f32 / steps as f32;
+        blend_pixel(image, (from.0 + dx * t).round() as i64, (from.1 + dy * t).round() as i64, colour);
+    }
+}